@@ -0,0 +1,35 @@
+use std::env;
+
+/// The cfgs the crate uses for code that differs between PHP versions, each
+/// set when PHP is at least that version.
+const VERSION_CFGS: &[(&str, u32)] = &[
+    ("php74", 70400),
+    ("php80", 80000),
+    ("php81", 80100),
+    ("php82", 80200),
+    ("php83", 80300),
+];
+
+fn main() {
+    // set by php-sys through its links metadata
+    let version_id = env::var("DEP_PHP7_VERSION_ID")
+        .ok()
+        .and_then(|id| id.parse().ok())
+        .unwrap_or(70200);
+    // whether the libphp php-sys built or found is thread safe, which does not
+    // follow this crate's zts feature if another crate enabled php-sys's
+    let zts = env::var("DEP_PHP7_ZTS")
+        .map(|zts| zts == "1")
+        .unwrap_or(false);
+
+    let names: Vec<&str> = VERSION_CFGS.iter().map(|&(name, _)| name).collect();
+    println!("cargo:rustc-check-cfg=cfg({}, php_zts)", names.join(", "));
+    if zts {
+        println!("cargo:rustc-cfg=php_zts");
+    }
+    for &(name, since) in VERSION_CFGS {
+        if version_id >= since {
+            println!("cargo:rustc-cfg={}", name);
+        }
+    }
+}
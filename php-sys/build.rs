@@ -1,15 +1,21 @@
+#[cfg(feature = "regenerate-bindings")]
 extern crate bindgen;
 extern crate cc;
 extern crate num_cpus;
 
+#[cfg(feature = "regenerate-bindings")]
 use bindgen::callbacks::{MacroParsingBehavior, ParseCallbacks};
+#[cfg(feature = "regenerate-bindings")]
 use bindgen::Builder;
+#[cfg(feature = "regenerate-bindings")]
 use std::collections::HashSet;
 use std::env;
+use std::fs;
 use std::io::Write;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+#[cfg(feature = "regenerate-bindings")]
 use std::sync::{Arc, RwLock};
 
 const PHP_VERSION: &'static str = concat!("php-", env!("CARGO_PKG_VERSION"));
@@ -44,20 +50,566 @@ fn target(path: &str) -> String {
         Ok(d) => d,
         Err(_) => String::from("target"),
     };
-    let profile = env::var("PROFILE").unwrap();
-    format!("{}/{}/{}/native/{}", osdir, pfx, profile, path)
+    // one build per flavour, so switching never mixes their objects
+    format!("{}/{}/native/{}/{}", osdir, pfx, flavour(), path)
+}
+
+/// Whether PHP is built with `--enable-debug`. `PHP_DEBUG=1` or `PHP_DEBUG=0`
+/// decides if it is set, otherwise debug builds of the crate get a debug PHP
+/// and release builds an optimized one.
+fn php_debug() -> bool {
+    match env::var("PHP_DEBUG") {
+        Ok(debug) => debug != "0" && !debug.is_empty(),
+        Err(_) => env::var("PROFILE").unwrap() == "debug",
+    }
+}
+
+/// Whether PHP is built thread safe, which the `zts` feature asks for.
+fn php_zts() -> bool {
+    env::var_os("CARGO_FEATURE_ZTS").is_some()
+}
+
+fn flavour() -> String {
+    flavour_of(php_debug())
+}
+
+/// The flavour of a PHP built with or without `--enable-debug`.
+fn flavour_of(debug: bool) -> String {
+    format!(
+        "{}-{}",
+        if debug { "debug" } else { "release" },
+        if php_zts() { "zts" } else { "nts" }
+    )
 }
 
 fn exists(path: &str) -> bool {
     Path::new(target(path).as_str()).exists()
 }
 
+/// Puts the PHP sources into `php-src` in the target dir. They are copied from
+/// `PHP_SRC_DIR` or unpacked from `PHP_SRC_TARBALL` if one is set, which needs
+/// no network access, and cloned from GitHub otherwise.
+fn fetch_sources(php_version: &str) {
+    fs::create_dir_all(target("")).expect("failed to create the native build dir");
+    if let Ok(dir) = env::var("PHP_SRC_DIR") {
+        let dir = absolute(&dir);
+        println_stderr!("Copying PHP sources from {}", dir);
+        // copied so the build never touches the tree it was given
+        run_command_or_fail(
+            target(""),
+            "cp",
+            &["-R", &format!("{}/.", dir), &target("php-src")],
+        );
+    } else if let Ok(tarball) = env::var("PHP_SRC_TARBALL") {
+        let tarball = absolute(&tarball);
+        println_stderr!("Unpacking PHP sources from {}", tarball);
+        fs::create_dir_all(target("php-src")).expect("failed to create php-src");
+        // release tarballs have a php-<version> directory at the top
+        run_command_or_fail(
+            target("php-src"),
+            "tar",
+            &["-xf", &tarball, "--strip-components=1"],
+        );
+    } else {
+        println_stderr!("Setting up PHP {}", php_version);
+        run_command_or_fail(
+            target(""),
+            "git",
+            &[
+                "clone",
+                "https://github.com/php/php-src",
+                format!("--branch={}", php_version).as_str(),
+            ],
+        );
+    }
+}
+
+/// The commands run in the target dir, so paths from the environment have to
+/// be absolute.
+fn absolute(path: &str) -> String {
+    match fs::canonicalize(path) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(e) => panic!("{}: {}", path, e),
+    }
+}
+
+/// An extension from `PHP_EXTRA_EXT_DIRS` that is compiled into libphp.
+struct Extension {
+    /// The directory with the extension's `config.m4`.
+    dir: String,
+    /// The name its `config.m4` gives it, also the dir in `php-src/ext`.
+    name: String,
+    /// The configure flag enabling it, `--enable-<name>` or `--with-<name>`.
+    flag: String,
+}
+
+/// The extensions listed in `PHP_EXTRA_EXT_DIRS`, separated like `PATH`.
+fn extra_extensions() -> Vec<Extension> {
+    let dirs = match env::var_os("PHP_EXTRA_EXT_DIRS") {
+        Some(dirs) => dirs,
+        None => return Vec::new(),
+    };
+    env::split_paths(&dirs)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| {
+            let dir = absolute(&dir.to_string_lossy());
+            let path = format!("{}/config.m4", dir);
+            let config = fs::read_to_string(&path).unwrap_or_else(|e| {
+                panic!(
+                    "{}: {}, PHP_EXTRA_EXT_DIRS has to list extension sources",
+                    path, e
+                )
+            });
+            let (kind, name) = extension_arg(&config)
+                .unwrap_or_else(|| panic!("no PHP_ARG_ENABLE or PHP_ARG_WITH in {}", path));
+            Extension {
+                dir,
+                flag: format!("--{}-{}", kind, name),
+                name,
+            }
+        })
+        .collect()
+}
+
+/// Finds the first `PHP_ARG_ENABLE(name, ...)` or `PHP_ARG_WITH(name, ...)` in
+/// a `config.m4`, as ("enable" or "with", name).
+fn extension_arg(config: &str) -> Option<(&'static str, String)> {
+    let line = config
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("PHP_ARG_ENABLE(") || line.starts_with("PHP_ARG_WITH("))?;
+    let kind = if line.starts_with("PHP_ARG_ENABLE(") {
+        "enable"
+    } else {
+        "with"
+    };
+    let args = &line[line.find('(')? + 1..];
+    let name = args.split(',').next()?;
+    let name = name.trim().trim_matches(|c| c == '[' || c == ']').trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some((kind, name.to_owned()))
+    }
+}
+
+/// A cargo feature with the configure flags if it is enabled and if it is not.
+type FeatureFlags = (
+    &'static str,
+    &'static [&'static str],
+    &'static [&'static str],
+);
+
+const FEATURE_FLAGS: &'static [FeatureFlags] = &[
+    (
+        "XML",
+        &[],
+        &[
+            "--disable-libxml",
+            "--disable-dom",
+            "--disable-xml",
+            "--disable-simplexml",
+            "--disable-xmlwriter",
+            "--disable-xmlreader",
+        ],
+    ),
+    ("MBSTRING", &["--enable-mbstring"], &[]),
+    ("INTL", &["--enable-intl"], &[]),
+    ("PDO_SQLITE", &["--with-pdo-sqlite"], &[]),
+    ("PDO_MYSQL", &["--with-pdo-mysql"], &[]),
+    ("OPCACHE", &["--enable-opcache"], &["--disable-opcache"]),
+    ("SODIUM", &["--with-sodium"], &[]),
+    ("OPENSSL", &["--with-openssl"], &[]),
+    ("CURL", &["--with-curl"], &[]),
+    ("ZLIB", &["--with-zlib"], &[]),
+];
+
+/// Configure flags PHP renamed, as (old flag, new flag, PHP_VERSION_ID that
+/// renamed it).
+const RENAMED_FLAGS: &'static [(&'static str, &'static str, u32)] = &[
+    ("--disable-libxml", "--without-libxml", 70400),
+    ("--enable-maintainer-zts", "--enable-zts", 80000),
+];
+
+/// The oldest and the newest PHP the shims cover, as PHP_VERSION_ID.
+const MIN_VERSION_ID: u32 = 70200;
+const MAX_VERSION_ID: u32 = 80399;
+
+/// Reads PHP_VERSION_ID from `main/php_version.h` below `include_dir`, which
+/// is the same in a source tree and an install.
+fn php_version_id(include_dir: &str) -> u32 {
+    let path = format!("{}/main/php_version.h", include_dir);
+    let header = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    let version_id = header
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("#define PHP_VERSION_ID"))
+        .filter_map(|id| id.trim().parse().ok())
+        .next()
+        .unwrap_or_else(|| panic!("no PHP_VERSION_ID in {}", path));
+    if version_id < MIN_VERSION_ID || version_id > MAX_VERSION_ID {
+        panic!(
+            "PHP {} is not supported, php-sys supports PHP 7.2 to 8.3",
+            version_id
+        );
+    }
+    version_id
+}
+
+/// The name of libphp, PHP 8 dropped the major version from it.
+fn lib_name(version_id: u32) -> &'static str {
+    if version_id >= 80000 {
+        "php"
+    } else {
+        "php7"
+    }
+}
+
+/// The configure flags for the enabled cargo features, the extensions from
+/// `PHP_EXTRA_EXT_DIRS` and `PHP_CONFIGURE_ARGS`.
+fn configure_args(link_static: bool, version_id: u32, extensions: &[Extension]) -> Vec<String> {
+    let embed_type = if link_static { "static" } else { "shared" };
+    let mut args = vec![
+        format!("--enable-embed={}", embed_type),
+        "--disable-cli".to_owned(),
+        "--disable-cgi".to_owned(),
+    ];
+    if php_zts() {
+        args.push("--enable-maintainer-zts".to_owned());
+    }
+    if php_debug() {
+        args.push("--enable-debug".to_owned());
+    }
+    #[cfg(all(target_os = "macos"))]
+    args.push("--without-iconv".to_owned());
+
+    for &(feature, enabled, disabled) in FEATURE_FLAGS {
+        let flags = if env::var_os(format!("CARGO_FEATURE_{}", feature)).is_some() {
+            enabled
+        } else {
+            disabled
+        };
+        args.extend(flags.iter().map(|flag| flag.to_string()));
+    }
+    for arg in args.iter_mut() {
+        for &(old, new, since) in RENAMED_FLAGS {
+            if *arg == old && version_id >= since {
+                *arg = new.to_owned();
+            }
+        }
+    }
+    args.extend(extensions.iter().map(|ext| ext.flag.clone()));
+    if let Ok(extra) = env::var("PHP_CONFIGURE_ARGS") {
+        args.extend(extra.split_whitespace().map(|arg| arg.to_owned()));
+    }
+    args
+}
+
+/// Where the PHP sources come from, as it is kept in the source stamp.
+fn source(php_version: &str) -> String {
+    if let Ok(dir) = env::var("PHP_SRC_DIR") {
+        format!("dir {}", dir)
+    } else if let Ok(tarball) = env::var("PHP_SRC_TARBALL") {
+        format!("tarball {}", tarball)
+    } else {
+        format!("git {}", php_version)
+    }
+}
+
+/// Builds PHP with the embed SAPI in the target dir. The configure flags are
+/// kept in a stamp file, PHP is configured and built again whenever they
+/// change. Sources fetched from elsewhere are replaced.
+fn build_from_source(php_version: &str, link_static: bool, cpus: &str) {
+    let source = source(php_version);
+    let source_stamp = target("php-src.source");
+    let fresh = !exists("php-src/LICENSE")
+        || fs::read_to_string(&source_stamp).ok().as_ref() != Some(&source);
+    if fresh {
+        if exists("php-src") {
+            println_stderr!("PHP sources changed to {}, fetching them again", source);
+            fs::remove_dir_all(target("php-src")).expect("failed to remove the old php-src");
+        }
+        fetch_sources(php_version);
+        run_command_or_fail(
+            target("php-src"),
+            "sed",
+            &[
+                "-e",
+                // exported by PHP itself since 7.3
+                "s/^void zend_signal_startup/ZEND_API void zend_signal_startup/g",
+                "-ibk",
+                "Zend/zend_signal.c",
+                "Zend/zend_signal.h",
+            ],
+        );
+        // release tarballs ship the generated files already
+        if exists("php-src/genfiles") {
+            run_command_or_fail(target("php-src"), "./genfiles", &[]);
+        }
+    }
+
+    // buildconf only picks up extensions that are in ext/ already. They are
+    // copied again whenever they changed, a changed config.m4 needs buildconf
+    // and configure, other changes only make.
+    let extensions = extra_extensions();
+    let mut added = false;
+    let mut changed = false;
+    for ext in &extensions {
+        println!("cargo:rerun-if-changed={}", ext.dir);
+        let ext_dir = format!("php-src/ext/{}", ext.name);
+        let copy = PathBuf::from(target(&ext_dir));
+        if exists(&ext_dir) && copied(Path::new(&ext.dir), &copy) {
+            continue;
+        }
+        let reconfigure = !exists(&ext_dir)
+            || ["config.m4", "config0.m4"]
+                .iter()
+                .any(|f| !same_file(&Path::new(&ext.dir).join(f), &copy.join(f)));
+        println_stderr!("Copying the {} extension from {}", ext.name, ext.dir);
+        run_command_or_fail(
+            target(""),
+            "cp",
+            &["-R", &format!("{}/.", ext.dir), &target(&ext_dir)],
+        );
+        if reconfigure {
+            added = true;
+        } else {
+            changed = true;
+        }
+    }
+    if fresh || added {
+        run_command_or_fail(target("php-src"), "./buildconf", &["--force"]);
+    }
+    if fresh {
+        fs::write(&source_stamp, &source).expect("failed to write the source stamp");
+    }
+
+    let args = configure_args(link_static, php_version_id(&target("php-src")), &extensions);
+    let stamp = target("php-src/.php-rs-configure");
+    let configured = args.join("\n");
+    if !added && fs::read_to_string(&stamp).ok().as_ref() == Some(&configured) {
+        if changed {
+            run_command_or_fail(target("php-src"), "make", &["-j", cpus]);
+        }
+        return;
+    }
+    if exists("php-src/Makefile") {
+        // objects built with other flags must not end up in libphp
+        run_command_or_fail(target("php-src"), "make", &["clean"]);
+    }
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    run_command_or_fail(target("php-src"), "./configure", &args);
+    run_command_or_fail(target("php-src"), "make", &["-j", cpus]);
+    fs::write(&stamp, configured).expect("failed to write the configure stamp");
+}
+
+/// Whether two files have the same content, or are both missing.
+fn same_file(a: &Path, b: &Path) -> bool {
+    fs::read(a).ok() == fs::read(b).ok()
+}
+
+/// Whether every file below `src` is in `dst` with the same content. Files
+/// only in `dst`, like the objects of an extension, don't matter.
+fn copied(src: &Path, dst: &Path) -> bool {
+    let entries = match fs::read_dir(src) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|entry| entry.ok()).all(|entry| {
+        let path = entry.path();
+        let copy = dst.join(entry.file_name());
+        if path.is_dir() {
+            copied(&path, &copy)
+        } else {
+            copy.is_file() && same_file(&path, &copy)
+        }
+    })
+}
+
+/// An installed PHP with the embed SAPI, found through `PHP_CONFIG` or
+/// `PHP_INCLUDE_DIR` and `PHP_LIB_DIR`.
+struct Install {
+    /// The include dirs, the top one first.
+    include_dirs: Vec<String>,
+    lib_dir: String,
+    /// The name of libphp, e.g. `php7` or `php7.2` on Debian.
+    lib: String,
+    /// The libraries libphp itself links to.
+    libs: Vec<String>,
+    /// The OPcache zend_extension, if the install has one.
+    opcache: Option<String>,
+}
+
+impl Install {
+    /// The `main/php_config.h` of the install.
+    fn php_config_h(&self) -> String {
+        let include_dir = &self.include_dirs[0];
+        fs::read_to_string(format!("{}/main/php_config.h", include_dir))
+            .unwrap_or_else(|e| panic!("can't read php_config.h in {}: {}", include_dir, e))
+    }
+
+    /// Whether the install was configured with `--enable-debug`, which
+    /// changes the signatures of the allocator functions among others.
+    fn debug(&self) -> bool {
+        self.php_config_h().contains("#define ZEND_DEBUG 1")
+    }
+
+    /// Fails the build if PHP can't be embedded the way the crate needs it.
+    fn check(&self) {
+        let include_dir = &self.include_dirs[0];
+        if !Path::new(&format!("{}/sapi/embed/php_embed.h", include_dir)).exists() {
+            panic!(
+                "the PHP in {} has no embed SAPI, it has to be configured with --enable-embed",
+                include_dir
+            );
+        }
+        let zts = self.php_config_h().contains("#define ZTS 1");
+        if php_zts() && !zts {
+            panic!(
+                "the PHP in {} is not thread safe, it has to be configured with \
+                 --enable-zts (--enable-maintainer-zts before PHP 8) or php-sys built \
+                 without the zts feature",
+                include_dir
+            );
+        }
+        if !php_zts() && zts {
+            panic!(
+                "the PHP in {} is thread safe, php-sys has to be built with the zts feature",
+                include_dir
+            );
+        }
+    }
+}
+
+fn find_install() -> Option<Install> {
+    if let Ok(include_dir) = env::var("PHP_INCLUDE_DIR") {
+        let lib_dir = env::var("PHP_LIB_DIR").expect("PHP_INCLUDE_DIR needs PHP_LIB_DIR as well");
+        let include_dirs = ["", "/TSRM", "/Zend", "/main"]
+            .iter()
+            .map(|d| format!("{}{}", include_dir, d))
+            .collect();
+        let (lib_dir, lib) = find_lib(&[lib_dir]);
+        let opcache = extension_dir(&include_dir).map(|dir| format!("{}/opcache.so", dir));
+        return Some(Install {
+            include_dirs,
+            lib_dir,
+            lib,
+            libs: Vec::new(),
+            opcache: opcache.filter(|opcache| Path::new(opcache).exists()),
+        });
+    }
+
+    let php_config = env::var("PHP_CONFIG").ok()?;
+    let include_dirs: Vec<String> = php_config_flags(&php_config, "--includes", "-I");
+    if include_dirs.is_empty() {
+        panic!("{} --includes returned no include dirs", php_config);
+    }
+    let prefix = run_php_config(&php_config, "--prefix");
+    // where installs put libphp, then the dirs PHP was linked with
+    let mut lib_dirs = install_lib_dirs(prefix.trim());
+    lib_dirs.extend(php_config_flags(&php_config, "--ldflags", "-L"));
+    let (lib_dir, lib) = find_lib(&lib_dirs);
+    let opcache = format!(
+        "{}/opcache.so",
+        run_php_config(&php_config, "--extension-dir").trim()
+    );
+    Some(Install {
+        include_dirs,
+        lib_dir,
+        lib,
+        libs: php_config_flags(&php_config, "--libs", "-l"),
+        opcache: if Path::new(&opcache).exists() {
+            Some(opcache)
+        } else {
+            None
+        },
+    })
+}
+
+/// Reads the extension dir PHP was configured with from `main/build-defs.h`
+/// below `include_dir`.
+fn extension_dir(include_dir: &str) -> Option<String> {
+    let header = fs::read_to_string(format!("{}/main/build-defs.h", include_dir)).ok()?;
+    header
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("#define PHP_EXTENSION_DIR"))
+        .map(|value| value.trim().trim_matches('"').to_owned())
+        .next()
+}
+
+fn run_php_config(php_config: &str, arg: &str) -> String {
+    let output = Command::new(php_config)
+        .arg(arg)
+        .output()
+        .unwrap_or_else(|e| panic!("failed to run {} {}: {}", php_config, arg, e));
+    if !output.status.success() {
+        panic!("{} {} failed", php_config, arg);
+    }
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// The values of the flags starting with `prefix`, e.g. the dirs of `-I`.
+fn php_config_flags(php_config: &str, arg: &str, prefix: &str) -> Vec<String> {
+    run_php_config(php_config, arg)
+        .split_whitespace()
+        .filter(|flag| flag.starts_with(prefix))
+        .map(|flag| flag[prefix.len()..].to_owned())
+        .collect()
+}
+
+/// The lib dirs below `prefix`, distributions use `lib/<multiarch>` (e.g.
+/// `lib/x86_64-linux-gnu`) or `lib64` besides `lib`.
+fn install_lib_dirs(prefix: &str) -> Vec<String> {
+    let target = |name| env::var(name).unwrap_or_default();
+    let multiarch = format!(
+        "{}-{}-{}",
+        target("CARGO_CFG_TARGET_ARCH"),
+        target("CARGO_CFG_TARGET_OS"),
+        target("CARGO_CFG_TARGET_ENV")
+    );
+    vec![
+        format!("{}/lib", prefix),
+        format!("{}/lib/{}", prefix, multiarch),
+        format!("{}/lib64", prefix),
+    ]
+}
+
+/// Finds libphp in the first of `lib_dirs` that has one and returns that dir
+/// and the name to link. It is called libphp7 up to PHP 7, libphp from PHP 8
+/// and distributions add the minor version. A shared libphp is picked over a
+/// static one, the names are sorted so the pick does not depend on the order
+/// the dir is read in.
+fn find_lib(lib_dirs: &[String]) -> (String, String) {
+    for lib_dir in lib_dirs {
+        let mut files: Vec<String> = match fs::read_dir(lib_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .filter(|file| file.starts_with("libphp"))
+                .collect(),
+            Err(_) => continue,
+        };
+        files.sort();
+        for ext in &[".so", ".dylib", ".a"] {
+            if let Some(file) = files.iter().find(|file| file.ends_with(ext)) {
+                let lib = file["lib".len()..file.len() - ext.len()].to_owned();
+                return (lib_dir.clone(), lib);
+            }
+        }
+    }
+    panic!(
+        "no libphp in {}, PHP has to be configured with --enable-embed",
+        lib_dirs.join(", ")
+    );
+}
+
 /// This is needed to prevent bindgen to create multiple definitions of the same macro and fail
+#[cfg(feature = "regenerate-bindings")]
 #[derive(Debug)]
 struct MacroCallback {
     macros: Arc<RwLock<HashSet<String>>>,
 }
 
+#[cfg(feature = "regenerate-bindings")]
 impl ParseCallbacks for MacroCallback {
     fn will_parse_macro(&self, name: &str) -> MacroParsingBehavior {
         self.macros.write().unwrap().insert(name.into());
@@ -71,17 +623,132 @@ impl ParseCallbacks for MacroCallback {
     }
 }
 
+/// The functions bindgen generates bindings for, as regexes over the headers
+/// `wrapper.h` includes. Macros and static inline functions have no symbol to
+/// link against, the important ones are wrapped in `src/shim.c`.
+#[cfg(feature = "regenerate-bindings")]
+const BINDGEN_FUNCTIONS: &'static [&'static str] = &[
+    // the engine: zvals, strings, hash tables, objects, calls and compiling
+    "_?zend_.*",
+    "_?zval_.*",
+    "_?call_user_function.*",
+    "_?array_init.*",
+    "_?convert_to_.*",
+    "object_(init|properties_init).*",
+    "add_(assoc|index|next_index|property)_.*",
+    "_(e|safe_e|safe_)(malloc|calloc|realloc|free|strdup|strndup).*",
+    // PHP and the SAPI: startup, requests, ini, streams and output
+    "_?php_.*",
+    "_?sapi_.*",
+    "cfg_get_.*",
+    // TSRM
+    "ts_.*",
+    "tsrm_.*",
+];
+
+/// The constants and globals bindgen generates bindings for. The globals
+/// behind `EG`, `PG` and `CG` only exist without ZTS, `src/shim.c` has
+/// accessors that work with both.
+#[cfg(feature = "regenerate-bindings")]
+const BINDGEN_VARS: &'static [&'static str] = &[
+    "PHP_(MAJOR|MINOR|RELEASE)_VERSION",
+    "PHP_VERSION_ID",
+    "ZEND_MODULE_API_NO",
+    "IS_.*",
+    "E_.*",
+    "ZEND_ACC_.*",
+    "ZEND_INI_.*",
+    "PHP_INI_.*",
+    "SAPI_.*",
+    "PHP_CONNECTION_.*",
+    "HASH_KEY_.*",
+    "zend_stream_type_.*",
+    // hooks an embedder may replace, e.g. to cache compiled scripts
+    "zend_(compile_file|compile_string|execute_ex|execute_internal|error_cb|write)",
+    "zend_throw_exception_hook",
+    "zend_ce_.*",
+    "zend_standard_class_def",
+    "(executor|compiler|core|sapi)_globals",
+];
+
+/// The types bindgen generates bindings for, besides the ones the functions
+/// and vars above use.
+#[cfg(feature = "regenerate-bindings")]
+const BINDGEN_TYPES: &'static [&'static str] = &[
+    "zval",
+    "HashTable",
+    "Bucket",
+    "ZEND_RESULT_CODE",
+    "_?zend_.*",
+    "_?php_.*",
+    "_?sapi_.*",
+];
+
+/// The pre-generated bindings for a PHP version and the flavour, e.g.
+/// `bindings/php83-release-zts.rs`. The bindings differ between minor
+/// versions and with ZTS and debug, which change the layout of the globals
+/// and of some structs.
+fn bindings_file(version_id: u32, debug: bool) -> PathBuf {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    PathBuf::from(manifest_dir).join("bindings").join(format!(
+        "php{}{}-{}.rs",
+        version_id / 10000,
+        version_id / 100 % 100,
+        flavour_of(debug)
+    ))
+}
+
+/// Runs bindgen against the PHP headers and writes the bindings to `path`,
+/// which needs libclang.
+#[cfg(feature = "regenerate-bindings")]
+fn generate_bindings(include_dirs: &[String], path: &Path) {
+    let includes = include_dirs
+        .iter()
+        .map(|d| format!("-I{}", d))
+        .collect::<Vec<String>>();
+
+    let mut builder = Builder::default()
+        .rustfmt_bindings(true)
+        .clang_args(includes)
+        .parse_callbacks(Box::new(MacroCallback {
+            macros: Arc::new(RwLock::new(HashSet::new())),
+        }))
+        .derive_default(true)
+        .header("wrapper.h");
+    for function in BINDGEN_FUNCTIONS {
+        builder = builder.whitelist_function(function);
+    }
+    for var in BINDGEN_VARS {
+        builder = builder.whitelist_var(var);
+    }
+    for ty in BINDGEN_TYPES {
+        builder = builder.whitelist_type(ty);
+    }
+    let bindings = builder.generate().expect("Unable to generate bindings");
+
+    bindings
+        .write_to_file(path)
+        .expect("Couldn't write bindings!");
+}
+
 fn main() {
     let cpus = format!("{}", num_cpus::get());
     #[cfg(all(target_os = "linux"))]
     let default_link_static = false;
     #[cfg(all(target_os = "macos"))]
     let default_link_static = true;
-    let php_version = option_env!("PHP_VERSION").unwrap_or(PHP_VERSION);
-    let macros = Arc::new(RwLock::new(HashSet::new()));
+    let php_version = env::var("PHP_VERSION").unwrap_or_else(|_| PHP_VERSION.to_owned());
 
     println!("cargo:rerun-if-env-changed=PHP_VERSION");
     println!("cargo:rerun-if-env-changed=PHP_LINK_STATIC");
+    println!("cargo:rerun-if-env-changed=PHP_SRC_DIR");
+    println!("cargo:rerun-if-env-changed=PHP_SRC_TARBALL");
+    println!("cargo:rerun-if-env-changed=PHP_CONFIG");
+    println!("cargo:rerun-if-env-changed=PHP_INCLUDE_DIR");
+    println!("cargo:rerun-if-env-changed=PHP_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=PHP_CONFIGURE_ARGS");
+    println!("cargo:rerun-if-env-changed=PHP_EXTRA_EXT_DIRS");
+    println!("cargo:rerun-if-env-changed=PHP_DEBUG");
 
     let link_dynamic = env::var_os("PHP_LINK_DYNAMIC")
         .map(|_| true)
@@ -90,133 +757,87 @@ fn main() {
         .map(|_| true)
         .unwrap_or(default_link_static && !link_dynamic);
 
-    if !exists("php-src/LICENSE") {
-        println_stderr!("Setting up PHP {}", php_version);
-        run_command_or_fail(
-            target(""),
-            "git",
-            &[
-                "clone",
-                "https://github.com/php/php-src",
-                format!("--branch={}", php_version).as_str(),
-            ],
-        );
-        run_command_or_fail(
-            target("php-src"),
-            "sed",
-            &[
-                "-e",
-                "s/void zend_signal_startup/ZEND_API void zend_signal_startup/g",
-                "-ibk",
-                "Zend/zend_signal.c",
-                "Zend/zend_signal.h",
-            ],
-        );
-        run_command_or_fail(target("php-src"), "./genfiles", &[]);
-        run_command_or_fail(target("php-src"), "./buildconf", &["--force"]);
-
-        let embed_type = if link_static { "static" } else { "shared" };
-
-        #[cfg(all(target_os = "linux"))]
-        let config = &[
-            "--enable-debug",
-            &format!("--enable-embed={}", embed_type),
-            "--disable-cli",
-            "--disable-cgi",
-            "--enable-maintainer-zts",
-            // "--without-iconv",
-            "--disable-libxml",
-            "--disable-dom",
-            "--disable-xml",
-            "--disable-simplexml",
-            "--disable-xmlwriter",
-            "--disable-xmlreader",
-            // "--without-pear",
-            // "--with-libdir=lib64",
-            // "--with-pic",
-        ];
-        #[cfg(all(target_os = "macos"))]
-        let config = &[
-            "--enable-debug",
-            &format!("--enable-embed={}", embed_type),
-            "--disable-cli",
-            "--disable-cgi",
-            "--enable-maintainer-zts",
-            "--without-iconv",
-            "--disable-libxml",
-            "--disable-dom",
-            "--disable-xml",
-            "--disable-simplexml",
-            "--disable-xmlwriter",
-            "--disable-xmlreader",
-            // "--without-pear",
-            // "--with-libdir=lib64",
-            // "--with-pic",
-        ];
-        run_command_or_fail(target("php-src"), "./configure", config);
-        run_command_or_fail(target("php-src"), "make", &["-j", cpus.as_str()]);
-    }
+    let (include_dirs, lib_dir, lib, debug) = match find_install() {
+        Some(install) => {
+            if env::var_os("PHP_EXTRA_EXT_DIRS").is_some() {
+                panic!("PHP_EXTRA_EXT_DIRS needs PHP built from source, not an installed PHP");
+            }
+            install.check();
+            if link_static {
+                // a static libphp needs the libraries PHP itself links
+                for lib in &install.libs {
+                    println!("cargo:rustc-link-lib={}", lib);
+                }
+            }
+            if let Some(ref opcache) = install.opcache {
+                println!("cargo:rustc-env=PHP_SYS_OPCACHE={}", opcache);
+            }
+            // the install decides, not the profile
+            let debug = install.debug();
+            (install.include_dirs, install.lib_dir, install.lib, debug)
+        }
+        None => {
+            build_from_source(&php_version, link_static, &cpus);
+            let include_dirs = ["", "/TSRM", "/Zend", "/main"]
+                .iter()
+                .map(|d| format!("{}{}", target("php-src"), d))
+                .collect();
+            let lib = lib_name(php_version_id(&target("php-src")));
+            if env::var_os("CARGO_FEATURE_OPCACHE").is_some() {
+                // OPcache is always built as a shared zend_extension
+                println!(
+                    "cargo:rustc-env=PHP_SYS_OPCACHE={}",
+                    target("php-src/modules/opcache.so")
+                );
+            }
+            (
+                include_dirs,
+                target("php-src/libs"),
+                lib.to_owned(),
+                php_debug(),
+            )
+        }
+    };
 
-    let include_dir = target("php-src");
-    let lib_dir = target("php-src/libs");
+    // dependents get it as DEP_PHP7_VERSION_ID to pick their code paths
+    let version_id = php_version_id(&include_dirs[0]);
+    println!("cargo:version_id={}", version_id);
+    // and DEP_PHP7_ZTS, feature unification can turn zts on behind their back
+    println!("cargo:zts={}", if php_zts() { 1 } else { 0 });
 
     let link_type = if link_static { "=static" } else { "" };
 
-    println!("cargo:rustc-link-lib{}=php7", link_type);
+    println!("cargo:rustc-link-lib{}={}", link_type, lib);
     println!("cargo:rustc-link-search=native={}", lib_dir);
 
-    let includes = ["/", "/TSRM", "/Zend", "/main"]
-        .iter()
-        .map(|d| format!("-I{}{}", include_dir, d))
-        .collect::<Vec<String>>();
-
-    let bindings = Builder::default()
-        .rustfmt_bindings(true)
-        .clang_args(includes)
-        .whitelist_function("_zend_file_handle__bindgen_ty_1")
-        .whitelist_function("php_execute_script")
-        .whitelist_function("php_module_startup")
-        .whitelist_function("php_request_shutdown")
-        .whitelist_function("php_request_startup")
-        .whitelist_function("phprpm_fopen")
-        .whitelist_function("sapi_send_headers")
-        .whitelist_function("sapi_startup")
-        .whitelist_function("sg_request_info")
-        .whitelist_function("sg_sapi_headers")
-        .whitelist_function("sg_server_context")
-        .whitelist_function("sg_server_context")
-        .whitelist_function("sg_set_server_context")
-        .whitelist_function("sg_set_server_context")
-        .whitelist_function("ts_resource_ex")
-        .whitelist_function("tsrm_startup")
-        .whitelist_function("zend_error")
-        .whitelist_function("zend_signal_startup")
-        .whitelist_function("zend_tsrmls_cache_update")
-        .whitelist_var("SAPI_HEADER_SENT_SUCCESSFULLY")
-        .whitelist_type("sapi_headers_struc")
-        .whitelist_type("sapi_module_struc")
-        .whitelist_type("sapi_request_info")
-        .whitelist_type("ZEND_RESULT_CODE")
-        .whitelist_type("zval")
-        .whitelist_var("zend_stream_type_ZEND_HANDLE_FP")
-        .parse_callbacks(Box::new(MacroCallback {
-            macros: macros.clone(),
-        })).derive_default(true)
-        .header("wrapper.h")
-        .generate()
-        .expect("Unable to generate bindings");
-
-    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
-    bindings
-        .write_to_file(out_path.join("bindings.rs"))
-        .expect("Couldn't write bindings!");
+    let out_bindings = PathBuf::from(env::var("OUT_DIR").unwrap()).join("bindings.rs");
+    let bindings = bindings_file(version_id, debug);
+    if cfg!(feature = "regenerate-bindings") {
+        #[cfg(feature = "regenerate-bindings")]
+        generate_bindings(&include_dirs, &out_bindings);
+        // the tree is only written by regenerate-bindings.sh
+        println!(
+            "cargo:warning=bindings generated in {}, copy them to {}",
+            out_bindings.display(),
+            bindings.display()
+        );
+    } else if bindings.exists() {
+        println!("cargo:rerun-if-changed={}", bindings.display());
+        fs::copy(&bindings, &out_bindings).expect("Couldn't copy bindings!");
+    } else {
+        panic!(
+            "no pre-generated bindings for PHP {}.{} {} in {}, this combination is not \
+             supported. The regenerate-bindings feature generates them, which needs libclang.",
+            version_id / 10000,
+            version_id / 100 % 100,
+            flavour_of(debug),
+            bindings.display()
+        );
+    }
     cc::Build::new()
         .file("src/shim.c")
-        .include(&include_dir)
+        .includes(&include_dirs)
         .flag("-fPIC")
         .flag("-m64")
-        .include(&format!("{}/TSRM", include_dir))
-        .include(&format!("{}/Zend", include_dir))
-        .include(&format!("{}/main", include_dir))
         .compile("foo");
 }
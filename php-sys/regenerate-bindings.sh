@@ -0,0 +1,39 @@
+#!/bin/sh
+# Regenerates bindings/ for every supported PHP version and flavour. Needs
+# libclang and everything PHP needs to build. Extra arguments are passed to
+# cargo, e.g. the features enabling extensions.
+#
+# The build only writes the bindings to its OUT_DIR, they are copied into
+# bindings/ from there.
+set -e
+
+cd "$(dirname "$0")"
+mkdir -p bindings
+
+VERSIONS=${VERSIONS:-"php-7.2.34 php-7.3.33 php-7.4.33 php-8.0.30 php-8.1.29 php-8.2.24 php-8.3.12"}
+
+for version in $VERSIONS; do
+    # php-8.3.12 -> php83
+    name=$(echo "$version" | sed -E 's/^php-([0-9]+)\.([0-9]+)\..*$/php\1\2/')
+    for debug in 0 1; do
+        if [ "$debug" = 1 ]; then
+            profile=debug
+        else
+            profile=release
+        fi
+        for zts in zts nts; do
+            if [ "$zts" = zts ]; then
+                features="regenerate-bindings,zts"
+            else
+                features="regenerate-bindings"
+            fi
+            out_dir=$(PHP_VERSION=$version PHP_DEBUG=$debug \
+                cargo build --no-default-features --features "$features" \
+                    --message-format=json "$@" |
+                grep '"reason":"build-script-executed"' |
+                grep 'php-sys' |
+                sed -E 's/.*"out_dir":"([^"]*)".*/\1/')
+            cp "$out_dir/bindings.rs" "bindings/$name-$profile-$zts.rs"
+        done
+    done
+done
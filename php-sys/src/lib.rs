@@ -1,7 +1,29 @@
 #![allow(non_upper_case_globals)]
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
-use std::os::raw::{c_char, c_uchar, c_void};
+use std::os::raw::{c_char, c_int, c_uchar, c_void};
+
+pub const PHPRS_EVAL_OK: c_int = 0;
+pub const PHPRS_EVAL_PARSE_ERROR: c_int = 1;
+pub const PHPRS_EVAL_BAILOUT: c_int = 2;
+pub const PHPRS_CALL_BAILOUT: c_int = 1;
+
+/// The OPcache zend_extension of the PHP php-sys was built against, if it has
+/// one.
+pub const OPCACHE: Option<&'static str> = option_env!("PHP_SYS_OPCACHE");
+
+/// What `opcache_get_status()` reports, filled by `phprs_opcache_status`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct phprs_opcache_status_t {
+    pub enabled: c_int,
+    pub hits: zend_long,
+    pub misses: zend_long,
+    pub cached_scripts: zend_long,
+    pub used_memory: zend_long,
+    pub free_memory: zend_long,
+    pub wasted_memory: zend_long,
+}
 
 extern "C" {
     pub fn sg_request_info() -> *mut sapi_request_info;
@@ -10,8 +32,164 @@ extern "C" {
     pub fn sg_sapi_headers() -> *mut sapi_headers_struct;
     pub fn sg_headers_sent() -> c_uchar;
     pub fn sg_set_headers_sent(is_sent: c_uchar);
+    pub fn sg_options() -> c_int;
+    pub fn sg_set_options(options: c_int);
+    pub fn eg_exit_status() -> c_int;
+    pub fn eg_exception() -> *mut zend_object;
+    pub fn eg_function_table() -> *mut HashTable;
+    pub fn eg_class_table() -> *mut HashTable;
+    pub fn eg_zend_constants() -> *mut HashTable;
+    pub fn eg_ini_directives() -> *mut HashTable;
+    pub fn eg_symbol_table() -> *mut zend_array;
+    pub fn eg_current_execute_data() -> *mut zend_execute_data;
+    pub fn eg_error_reporting() -> c_int;
+    pub fn eg_set_error_reporting(error_reporting: c_int);
+    pub fn cg_function_table() -> *mut HashTable;
+    pub fn cg_class_table() -> *mut HashTable;
+    pub fn cg_compiler_options() -> u32;
+    pub fn cg_set_compiler_options(options: u32);
+    pub fn pg_display_errors() -> c_int;
+    pub fn pg_memory_limit() -> zend_long;
+    pub fn pg_connection_status() -> c_int;
+    pub fn phprs_clear_last_error();
+    pub fn phprs_last_error_is_fatal() -> c_int;
+    pub fn pg_last_error_message() -> *const c_char;
+    pub fn pg_last_error_file() -> *const c_char;
+    pub fn pg_last_error_lineno() -> c_int;
+    pub fn phprs_register_file_handles();
+    pub fn phprs_guard_sapi_module(module: *mut sapi_module_struct);
+    pub fn phprs_abort_connection();
+    pub fn phprs_bailout();
+    pub fn phprs_hook_interrupt(cb: Option<unsafe extern "C" fn()>);
+    pub fn phprs_interrupt_flag() -> *mut c_void;
+    pub fn phprs_interrupt(flag: *mut c_void);
+    pub fn phprs_hook_error_cb(
+        cb: Option<
+            unsafe extern "C" fn(
+                type_: c_int,
+                error_filename: *const c_char,
+                error_lineno: u32,
+                message: *const c_char,
+                message_len: usize,
+            ),
+        >,
+    );
+    pub fn phprs_hook_uncaught_exception(cb: Option<unsafe extern "C" fn(exception: *mut zval)>);
+    pub fn phprs_describe_exception(
+        object: *mut zval,
+        exception: Option<
+            unsafe extern "C" fn(
+                arg: *mut c_void,
+                class_name: *const c_char,
+                message: *const c_char,
+                code: zend_long,
+                file: *const c_char,
+                line: zend_long,
+            ),
+        >,
+        frame: Option<
+            unsafe extern "C" fn(
+                arg: *mut c_void,
+                file: *const c_char,
+                line: zend_long,
+                class_name: *const c_char,
+                function: *const c_char,
+            ),
+        >,
+        arg: *mut c_void,
+    );
+    pub fn phprs_add_function(name: *const c_char, data: *mut c_void);
+    pub fn phprs_add_exception_class(name: *const c_char, parent: *const c_char);
+    pub fn phprs_hook_functions(
+        cb: Option<
+            unsafe extern "C" fn(
+                data: *mut c_void,
+                name: *const c_char,
+                args: *mut zval,
+                argc: u32,
+                return_value: *mut zval,
+            ),
+        >,
+    );
+    pub fn phprs_throw(
+        class_name: *const c_char,
+        message: *const c_char,
+        message_len: usize,
+        code: zend_long,
+    );
+    pub fn phprs_zval_type(zv: *mut zval) -> c_int;
+    pub fn phprs_zval_long(zv: *mut zval) -> zend_long;
+    pub fn phprs_zval_double(zv: *mut zval) -> f64;
+    pub fn phprs_zval_string(zv: *mut zval, len: *mut usize) -> *const c_char;
+    pub fn phprs_zval_set_null(zv: *mut zval);
+    pub fn phprs_zval_set_bool(zv: *mut zval, value: c_int);
+    pub fn phprs_zval_set_long(zv: *mut zval, value: zend_long);
+    pub fn phprs_zval_set_double(zv: *mut zval, value: f64);
+    pub fn phprs_zval_set_string(zv: *mut zval, value: *const c_char, len: usize);
+    pub fn phprs_z_type_p(zv: *const zval) -> zend_uchar;
+    pub fn phprs_z_str_p(zv: *const zval) -> *mut zend_string;
+    pub fn phprs_z_arrval_p(zv: *const zval) -> *mut HashTable;
+    pub fn phprs_z_obj_p(zv: *const zval) -> *mut zend_object;
+    pub fn phprs_z_objce_p(zv: *const zval) -> *mut zend_class_entry;
+    pub fn phprs_z_refval_p(zv: *mut zval) -> *mut zval;
+    pub fn phprs_z_try_addref_p(zv: *mut zval);
+    pub fn phprs_zval_copy(dst: *mut zval, src: *const zval);
+    pub fn phprs_zval_set_str(zv: *mut zval, s: *mut zend_string);
+    pub fn phprs_zval_set_array(zv: *mut zval);
+    pub fn phprs_zstr_val(s: *mut zend_string) -> *mut c_char;
+    pub fn phprs_zstr_len(s: *const zend_string) -> usize;
+    pub fn phprs_zstr_init(s: *const c_char, len: usize, persistent: c_int) -> *mut zend_string;
+    pub fn phprs_zstr_release(s: *mut zend_string);
+    pub fn phprs_hash_num_elements(ht: *const HashTable) -> u32;
+    pub fn phprs_hash_foreach(
+        ht: *mut HashTable,
+        cb: Option<
+            unsafe extern "C" fn(
+                arg: *mut c_void,
+                h: zend_ulong,
+                key: *mut zend_string,
+                val: *mut zval,
+            ) -> c_int,
+        >,
+        arg: *mut c_void,
+    );
+    pub fn phprs_try(cb: Option<unsafe extern "C" fn(arg: *mut c_void)>, arg: *mut c_void)
+        -> c_int;
+    pub fn phprs_register_variable(
+        var: *mut c_char,
+        val: *mut c_char,
+        val_len: usize,
+        track_vars_array: *mut zval,
+    );
+    pub fn phprs_module_startup(module: *mut sapi_module_struct) -> c_int;
+    pub fn phprs_request_startup() -> c_int;
+    pub fn phprs_execute_file(filename: *const c_char) -> c_int;
+    pub fn phprs_set_sapi_name(name: *mut c_char);
+    pub fn phprs_opcache_status(status: *mut phprs_opcache_status_t) -> c_int;
+    pub fn phprs_request_shutdown();
+    pub fn phprs_call_function(
+        function_name: *mut zval,
+        retval: *mut zval,
+        param_count: u32,
+        params: *mut zval,
+    ) -> c_int;
+    pub fn phprs_eval(
+        code: *const c_char,
+        code_len: usize,
+        expression: c_int,
+        cb: Option<unsafe extern "C" fn(arg: *mut c_void, s: *const c_char, len: usize)>,
+        arg: *mut c_void,
+    ) -> c_int;
+    #[cfg(feature = "zts")]
     pub fn zend_tsrmls_cache_update();
     pub fn phprpm_fopen(filename: *const c_char, mode: *const c_char) -> *mut FILE;
+    pub fn phprs_sapi_headers_each(
+        headers: *mut sapi_headers_struct,
+        cb: Option<
+            unsafe extern "C" fn(arg: *mut c_void, header: *const c_char, header_len: usize),
+        >,
+        arg: *mut c_void,
+    );
 }
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
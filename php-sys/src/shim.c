@@ -7,6 +7,27 @@
 #include <Zend/zend.h>
 #include <main/php.h>
 #include <sapi/embed/php_embed.h>
+#include <ext/standard/php_var.h>
+#include <Zend/zend_exceptions.h>
+
+/*
+ * The shims are written against PHP 7.2, the differences up to PHP 8.3 are
+ * covered with PHP_VERSION_ID checks so the Rust side doesn't have to care.
+ */
+
+#if PHP_VERSION_ID >= 80000
+/* property reads take the object instead of the zval since PHP 8 */
+# define PHPRS_OBJ(zv) Z_OBJ_P(zv)
+# define PHPRS_LOG_MESSAGE_CONST const
+#else
+# define PHPRS_OBJ(zv) (zv)
+# define PHPRS_LOG_MESSAGE_CONST
+#endif
+
+#ifndef CONST_CS
+/* constants are always case sensitive since PHP 8 */
+# define CONST_CS 0
+#endif
 
 sapi_request_info * sg_request_info() {
     return &SG(request_info);
@@ -32,9 +53,1163 @@ void sg_set_headers_sent(unsigned char is_sent) {
     SG(headers_sent) = is_sent;
 }
 
+/*
+ * Calls `cb` for every header line set for the response. Walking a zend_llist
+ * from Rust is painful, so this is done here.
+ */
+void phprs_sapi_headers_each(sapi_headers_struct *headers,
+                             void (*cb)(void *arg, const char *header, size_t header_len),
+                             void *arg) {
+    zend_llist_position pos;
+    sapi_header_struct *h;
+
+    h = (sapi_header_struct *) zend_llist_get_first_ex(&headers->headers, &pos);
+    while (h) {
+        cb(arg, h->header, h->header_len);
+        h = (sapi_header_struct *) zend_llist_get_next_ex(&headers->headers, &pos);
+    }
+}
+
+int sg_options() {
+    return SG(options);
+}
+
+void sg_set_options(int options) {
+    SG(options) = options;
+}
+
+int eg_exit_status() {
+    return EG(exit_status);
+}
+
+/*
+ * EG, CG and PG are macros that read the globals directly without ZTS and
+ * through the thread's resource with it, so Rust goes through these.
+ */
+
+zend_object * eg_exception() {
+    return EG(exception);
+}
+
+HashTable * eg_function_table() {
+    return EG(function_table);
+}
+
+HashTable * eg_class_table() {
+    return EG(class_table);
+}
+
+HashTable * eg_zend_constants() {
+    return EG(zend_constants);
+}
+
+HashTable * eg_ini_directives() {
+    return EG(ini_directives);
+}
+
+zend_array * eg_symbol_table() {
+    return &EG(symbol_table);
+}
+
+zend_execute_data * eg_current_execute_data() {
+    return EG(current_execute_data);
+}
+
+int eg_error_reporting() {
+    return EG(error_reporting);
+}
+
+void eg_set_error_reporting(int error_reporting) {
+    EG(error_reporting) = error_reporting;
+}
+
+HashTable * cg_function_table() {
+    return CG(function_table);
+}
+
+HashTable * cg_class_table() {
+    return CG(class_table);
+}
+
+uint32_t cg_compiler_options() {
+    return CG(compiler_options);
+}
+
+void cg_set_compiler_options(uint32_t options) {
+    CG(compiler_options) = options;
+}
+
+int pg_display_errors() {
+    return PG(display_errors);
+}
+
+zend_long pg_memory_limit() {
+    return PG(memory_limit);
+}
+
+int pg_connection_status() {
+    return PG(connection_status);
+}
+
+/*
+ * Forgets the last error like error_clear_last() does, so a bailout can be
+ * told apart from an error that happened earlier.
+ */
+void phprs_clear_last_error() {
+    if (PG(last_error_message)) {
+#if PHP_VERSION_ID >= 80000
+        zend_string_release(PG(last_error_message));
+#else
+        free(PG(last_error_message));
+#endif
+        PG(last_error_message) = NULL;
+    }
+    if (PG(last_error_file)) {
+#if PHP_VERSION_ID >= 80100
+        zend_string_release(PG(last_error_file));
+#else
+        free(PG(last_error_file));
+#endif
+        PG(last_error_file) = NULL;
+    }
+    PG(last_error_type) = 0;
+    PG(last_error_lineno) = 0;
+}
+
+/*
+ * Whether the last error is one that bails out, after a bailout this tells a
+ * fatal error apart from exit().
+ */
+int phprs_last_error_is_fatal() {
+    switch (PG(last_error_type)) {
+        case E_ERROR:
+        case E_CORE_ERROR:
+        case E_COMPILE_ERROR:
+        case E_USER_ERROR:
+        case E_RECOVERABLE_ERROR:
+        case E_PARSE:
+            return 1;
+        default:
+            return 0;
+    }
+}
+
+const char * pg_last_error_message() {
+#if PHP_VERSION_ID >= 80000
+    return PG(last_error_message) ? ZSTR_VAL(PG(last_error_message)) : NULL;
+#else
+    return PG(last_error_message);
+#endif
+}
+
+const char * pg_last_error_file() {
+#if PHP_VERSION_ID >= 80100
+    return PG(last_error_file) ? ZSTR_VAL(PG(last_error_file)) : NULL;
+#else
+    return PG(last_error_file);
+#endif
+}
+
+int pg_last_error_lineno() {
+    return PG(last_error_lineno);
+}
+
+static void register_stream_constant(const char *name, size_t name_len, php_stream *stream) {
+    zend_constant c;
+
+    php_stream_to_zval(stream, &c.value);
+#if PHP_VERSION_ID >= 70300
+    ZEND_CONSTANT_SET_FLAGS(&c, CONST_CS, 0);
+#else
+    c.flags = CONST_CS;
+    c.module_number = 0;
+#endif
+    c.name = zend_string_init(name, name_len, 1);
+    zend_register_constant(&c);
+}
+
+/*
+ * Defines the STDIN, STDOUT and STDERR constants for the current request, the
+ * same way php-cli does.
+ */
+static void register_file_handles() {
+    php_stream *s_in, *s_out, *s_err;
+
+    s_in  = php_stream_open_wrapper_ex("php://stdin",  "rb", 0, NULL, NULL);
+    s_out = php_stream_open_wrapper_ex("php://stdout", "wb", 0, NULL, NULL);
+    s_err = php_stream_open_wrapper_ex("php://stderr", "wb", 0, NULL, NULL);
+
+    if (s_in == NULL || s_out == NULL || s_err == NULL) {
+        if (s_in) php_stream_close(s_in);
+        if (s_out) php_stream_close(s_out);
+        if (s_err) php_stream_close(s_err);
+        return;
+    }
+
+    /* the embedder still writes to them after the script closed them */
+    s_out->flags |= PHP_STREAM_FLAG_NO_CLOSE;
+    s_err->flags |= PHP_STREAM_FLAG_NO_CLOSE;
+
+    register_stream_constant("STDIN", sizeof("STDIN")-1, s_in);
+    register_stream_constant("STDOUT", sizeof("STDOUT")-1, s_out);
+    register_stream_constant("STDERR", sizeof("STDERR")-1, s_err);
+}
+
+void phprs_register_file_handles() {
+    zend_try {
+        register_file_handles();
+    } zend_end_try();
+}
+
+#define PHPRS_EVAL_OK 0
+#define PHPRS_EVAL_PARSE_ERROR 1
+#define PHPRS_EVAL_BAILOUT 2
+
+/*
+ * Evaluates `code` in the current request. With `expression` set it is run as
+ * `return <code>;` and `cb` gets the var_export() of the value, otherwise it
+ * is run as statements. On a parse error `cb` gets the message and no
+ * exception is left behind. Uncaught exceptions are reported as warnings like
+ * the php-cli shell does, fatal errors and exit() are caught here so the
+ * longjmp never crosses Rust frames.
+ */
+int phprs_eval(const char *code, size_t code_len, int expression,
+               void (*cb)(void *arg, const char *s, size_t len), void *arg) {
+    volatile int status = PHPRS_EVAL_OK;
+    zend_execute_data *execute_data = EG(current_execute_data);
+
+    zend_try {
+        zval retval;
+
+        ZVAL_UNDEF(&retval);
+        if (zend_eval_stringl((char *) code, code_len, expression ? &retval : NULL,
+                              "php-rs eval") == FAILURE) {
+            status = PHPRS_EVAL_PARSE_ERROR;
+            if (EG(exception)) {
+                zval exception, rv, *message;
+
+                ZVAL_OBJ(&exception, EG(exception));
+                message = zend_read_property(zend_get_exception_base(PHPRS_OBJ(&exception)),
+                                             PHPRS_OBJ(&exception),
+                                             "message", sizeof("message")-1, 1, &rv);
+                if (Z_TYPE_P(message) == IS_STRING) {
+                    cb(arg, Z_STRVAL_P(message), Z_STRLEN_P(message));
+                }
+                zend_clear_exception();
+            }
+        } else if (EG(exception)) {
+            zend_exception_error(EG(exception), E_WARNING);
+        } else if (expression) {
+            smart_str buf = {0};
+
+            php_var_export_ex(&retval, 1, &buf);
+            smart_str_0(&buf);
+            cb(arg, ZSTR_VAL(buf.s), ZSTR_LEN(buf.s));
+            smart_str_free(&buf);
+        }
+        zval_ptr_dtor(&retval);
+    } zend_catch {
+        EG(current_execute_data) = execute_data;
+        status = PHPRS_EVAL_BAILOUT;
+    } zend_end_try();
+
+    return status;
+}
+
+/*
+ * PHP leaves fatal errors, exit() and aborted connections with a longjmp to
+ * the closest zend_try. Skipping Rust frames that way is undefined behaviour,
+ * so every entry point Rust calls catches bailouts here. The SAPI callbacks
+ * are wrapped in C trampolines, Rust only asks for an abort or a bailout and
+ * the trampoline acts on it once the Rust callback returned.
+ */
+
+#define PHPRS_PENDING_ABORT 1
+#define PHPRS_PENDING_BAILOUT 2
+
+/* the callbacks of the module as Rust set them */
+static sapi_module_struct phprs_rust_module;
+
+#ifdef ZTS
+static TSRM_TLS int phprs_pending = 0;
+#else
+static int phprs_pending = 0;
+#endif
+
+void phprs_abort_connection() {
+    phprs_pending |= PHPRS_PENDING_ABORT;
+}
+
+void phprs_bailout() {
+    phprs_pending |= PHPRS_PENDING_BAILOUT;
+}
+
+/* Returns 1 if the connection was aborted and php_handle_aborted_connection
+ * did not bail out because of ignore_user_abort. */
+static int phprs_handle_pending() {
+    int pending = phprs_pending;
+
+    phprs_pending = 0;
+    if (pending & PHPRS_PENDING_BAILOUT) {
+        zend_bailout();
+    }
+    if (pending & PHPRS_PENDING_ABORT) {
+        php_handle_aborted_connection();
+        return 1;
+    }
+    return 0;
+}
+
+static int phprs_startup(sapi_module_struct *module) {
+    int result = phprs_rust_module.startup(module);
+    phprs_handle_pending();
+    return result;
+}
+
+static int phprs_shutdown(sapi_module_struct *module) {
+    int result = phprs_rust_module.shutdown(module);
+    phprs_handle_pending();
+    return result;
+}
+
+static size_t phprs_ub_write(const char *str, size_t str_length) {
+    size_t written = phprs_rust_module.ub_write(str, str_length);
+    phprs_handle_pending();
+    return written;
+}
+
+static void phprs_flush(void *server_context) {
+    phprs_rust_module.flush(server_context);
+    if (phprs_handle_pending()) {
+        return;
+    }
+    if (!SG(headers_sent)) {
+        sapi_send_headers();
+    }
+}
+
+static int phprs_send_headers(sapi_headers_struct *sapi_headers) {
+    int result = phprs_rust_module.send_headers(sapi_headers);
+    phprs_handle_pending();
+    return result;
+}
+
+static size_t phprs_read_post(char *buffer, size_t count_bytes) {
+    size_t read = phprs_rust_module.read_post(buffer, count_bytes);
+    phprs_handle_pending();
+    return read;
+}
+
+static char * phprs_read_cookies() {
+    char *cookies = phprs_rust_module.read_cookies();
+    phprs_handle_pending();
+    return cookies;
+}
+
+static void phprs_register_server_variables(zval *track_vars_array) {
+    phprs_rust_module.register_server_variables(track_vars_array);
+    phprs_handle_pending();
+}
+
+static void phprs_log_message(PHPRS_LOG_MESSAGE_CONST char *message, int syslog_type_int) {
+    phprs_rust_module.log_message(message, syslog_type_int);
+    phprs_handle_pending();
+}
+
+/*
+ * Puts the trampolines in front of the callbacks Rust set on the module. There
+ * is only one SAPI module per process.
+ */
+void phprs_guard_sapi_module(sapi_module_struct *module) {
+    phprs_rust_module = *module;
+    if (module->startup) module->startup = phprs_startup;
+    if (module->shutdown) module->shutdown = phprs_shutdown;
+    if (module->ub_write) module->ub_write = phprs_ub_write;
+    if (module->flush) module->flush = phprs_flush;
+    if (module->send_headers) module->send_headers = phprs_send_headers;
+    if (module->read_post) module->read_post = phprs_read_post;
+    if (module->read_cookies) module->read_cookies = phprs_read_cookies;
+    if (module->register_server_variables) module->register_server_variables = phprs_register_server_variables;
+    if (module->log_message) module->log_message = phprs_log_message;
+}
+
+/*
+ * PHP only looks at the connection when it writes, a script that writes
+ * nothing never notices the client went away. Another thread interrupts the
+ * running requests now and then, the engine calls the interrupt function
+ * between two opcodes and Rust asks the aborted callback from there.
+ */
+
+static void (*phprs_rust_interrupt)(void);
+static void (*phprs_original_interrupt)(zend_execute_data *execute_data);
+
+static void phprs_interrupt_function(zend_execute_data *execute_data) {
+    if (phprs_original_interrupt) {
+        phprs_original_interrupt(execute_data);
+    }
+    phprs_rust_interrupt();
+    phprs_handle_pending();
+}
+
+/*
+ * Installs `cb` as the Rust side of zend_interrupt_function, it has to be
+ * called after the module started so extensions hooking it come first.
+ */
+void phprs_hook_interrupt(void (*cb)(void)) {
+    phprs_rust_interrupt = cb;
+    if (zend_interrupt_function != phprs_interrupt_function) {
+        phprs_original_interrupt = zend_interrupt_function;
+        zend_interrupt_function = phprs_interrupt_function;
+    }
+}
+
+/* The interrupt flag of the calling thread, for phprs_interrupt. */
+void * phprs_interrupt_flag() {
+    return (void *) &EG(vm_interrupt);
+}
+
+/* Interrupts the thread `flag` belongs to, it may be called from any thread. */
+void phprs_interrupt(void *flag) {
+#if PHP_VERSION_ID >= 80200
+    zend_atomic_bool_store((zend_atomic_bool *) flag, 1);
+#else
+    *(volatile zend_bool *) flag = 1;
+#endif
+}
+
+/*
+ * php_register_variable_safe for Rust callbacks, a bailout is held back until
+ * the callback returned to its trampoline.
+ */
+void phprs_register_variable(char *var, char *val, size_t val_len, zval *track_vars_array) {
+    zend_try {
+        php_register_variable_safe(var, val, val_len, track_vars_array);
+    } zend_catch {
+        phprs_bailout();
+    } zend_end_try();
+}
+
+static const char * phprs_property_string(zend_class_entry *base, zval *object, const char *name,
+                                          size_t name_len) {
+    zval rv, *value;
+
+    value = zend_read_property(base, PHPRS_OBJ(object), name, name_len, 1, &rv);
+    return Z_TYPE_P(value) == IS_STRING ? Z_STRVAL_P(value) : NULL;
+}
+
+static zend_long phprs_property_long(zend_class_entry *base, zval *object, const char *name,
+                                     size_t name_len) {
+    zval rv, *value;
+
+    value = zend_read_property(base, PHPRS_OBJ(object), name, name_len, 1, &rv);
+    return Z_TYPE_P(value) == IS_LONG ? Z_LVAL_P(value) : 0;
+}
+
+/*
+ * PHP reports an uncaught exception as a fatal error with the exception
+ * rendered as a string. To hand it to Rust intact, the last exception thrown
+ * in a request is remembered through zend_throw_exception_hook. When the fatal
+ * error for an uncaught exception comes in with the file and line of that
+ * exception, it is described to Rust before the error. A handler set with
+ * set_exception_handler() catches it before it gets that far.
+ */
+
+#if PHP_VERSION_ID >= 80000
+# define PHPRS_THROW_HOOK_ARGS zend_object *ex
+# define PHPRS_THROWN(ex) (ex)
+#else
+# define PHPRS_THROW_HOOK_ARGS zval *ex
+# define PHPRS_THROWN(ex) ((ex) ? Z_OBJ_P(ex) : NULL)
+#endif
+
+#ifndef GC_ADDREF
+/* PHP 7.2 */
+# define GC_ADDREF(p) (++GC_REFCOUNT(p))
+#endif
+
+static void (*phprs_original_throw_hook)(PHPRS_THROW_HOOK_ARGS) = NULL;
+static void (*phprs_rust_exception)(zval *exception) = NULL;
+
+#ifdef ZTS
+static TSRM_TLS zend_object *phprs_last_thrown = NULL;
+#else
+static zend_object *phprs_last_thrown = NULL;
+#endif
+
+/* Replaces the remembered exception, NULL just forgets it. */
+static void phprs_remember_thrown(zend_object *object) {
+    zend_object *previous = phprs_last_thrown;
+
+    if (object) {
+        GC_ADDREF(object);
+    }
+    phprs_last_thrown = object;
+    /* the release may run a destructor that throws again */
+    if (previous) {
+        OBJ_RELEASE(previous);
+    }
+}
+
+static void phprs_throw_hook(PHPRS_THROW_HOOK_ARGS) {
+    zend_object *object = PHPRS_THROWN(ex);
+
+    if (object && object != phprs_last_thrown) {
+        phprs_remember_thrown(object);
+    }
+    if (phprs_original_throw_hook) {
+        phprs_original_throw_hook(ex);
+    }
+}
+
+/* Describes the remembered exception to Rust if the error reports it uncaught. */
+static void phprs_report_uncaught(int type, const char *error_filename, uint32_t error_lineno,
+                                  const char *message) {
+    zval exception;
+    zend_class_entry *base;
+    const char *file;
+    zend_long line;
+
+    if (!phprs_last_thrown || !phprs_rust_exception || (type & E_ALL) != E_ERROR
+            || strncmp(message, "Uncaught ", sizeof("Uncaught ")-1) != 0) {
+        return;
+    }
+    ZVAL_OBJ(&exception, phprs_last_thrown);
+    base = zend_get_exception_base(PHPRS_OBJ(&exception));
+    file = phprs_property_string(base, &exception, "file", sizeof("file")-1);
+    line = phprs_property_long(base, &exception, "line", sizeof("line")-1);
+    if (!file || !error_filename || strcmp(file, error_filename) != 0
+            || line != (zend_long) error_lineno) {
+        return;
+    }
+    phprs_rust_exception(&exception);
+    phprs_remember_thrown(NULL);
+}
+
+/*
+ * Installs `cb` as the Rust side of uncaught exceptions, it has to be called
+ * after the module started so extensions hooking throws come first.
+ */
+void phprs_hook_uncaught_exception(void (*cb)(zval *exception)) {
+    phprs_rust_exception = cb;
+    if (zend_throw_exception_hook != phprs_throw_hook) {
+        phprs_original_throw_hook = zend_throw_exception_hook;
+        zend_throw_exception_hook = phprs_throw_hook;
+    }
+}
+
+#if PHP_VERSION_ID >= 80100
+# define PHPRS_ERROR_CB_ARGS int type, zend_string *error_filename, \
+                            const uint32_t error_lineno, zend_string *message
+#elif PHP_VERSION_ID >= 80000
+# define PHPRS_ERROR_CB_ARGS int type, const char *error_filename, \
+                            const uint32_t error_lineno, zend_string *message
+#else
+# define PHPRS_ERROR_CB_ARGS int type, const char *error_filename, \
+                            const uint32_t error_lineno, const char *format, va_list args
+#endif
+
+static void (*phprs_original_error_cb)(PHPRS_ERROR_CB_ARGS);
+static void (*phprs_rust_error)(int type, const char *error_filename, uint32_t error_lineno,
+                                const char *message, size_t message_len);
+
+/*
+ * Hands every diagnostic to Rust before PHP displays or logs it, a user error
+ * handler that handled it keeps it from getting here.
+ */
+static void phprs_error_cb(PHPRS_ERROR_CB_ARGS) {
+#if PHP_VERSION_ID >= 80000
+# if PHP_VERSION_ID >= 80100
+    const char *filename = error_filename ? ZSTR_VAL(error_filename) : NULL;
+# else
+    const char *filename = error_filename;
+# endif
+
+    phprs_report_uncaught(type, filename, error_lineno, ZSTR_VAL(message));
+    phprs_rust_error(type, filename, error_lineno, ZSTR_VAL(message), ZSTR_LEN(message));
+    phprs_handle_pending();
+
+    phprs_original_error_cb(type, error_filename, error_lineno, message);
+#else
+    va_list copy;
+    char *message;
+    size_t message_len;
+
+    va_copy(copy, args);
+    message_len = zend_vspprintf(&message, 0, format, copy);
+    va_end(copy);
+    phprs_report_uncaught(type, error_filename, error_lineno, message);
+    phprs_rust_error(type, error_filename, error_lineno, message, message_len);
+    efree(message);
+    phprs_handle_pending();
+
+    phprs_original_error_cb(type, error_filename, error_lineno, format, args);
+#endif
+}
+
+/*
+ * Installs `cb` as the Rust side of zend_error_cb, it has to be called after
+ * the module started as that sets zend_error_cb.
+ */
+void phprs_hook_error_cb(void (*cb)(int type, const char *error_filename, uint32_t error_lineno,
+                                    const char *message, size_t message_len)) {
+    phprs_rust_error = cb;
+    if (!phprs_original_error_cb) {
+        phprs_original_error_cb = zend_error_cb;
+        zend_error_cb = phprs_error_cb;
+    }
+}
+
+static const char * phprs_array_string(HashTable *ht, const char *key) {
+    zval *value = zend_hash_str_find(ht, key, strlen(key));
+
+    return value && Z_TYPE_P(value) == IS_STRING ? Z_STRVAL_P(value) : NULL;
+}
+
+/*
+ * Calls `exception` for `object` and every exception chained to it with
+ * getPrevious(), outermost first. After each of them `frame` is called for
+ * every frame of its getTrace(). Strings are NULL if PHP has none, a line of
+ * 0 means there is none.
+ */
+void phprs_describe_exception(zval *object,
+                              void (*exception)(void *arg, const char *class_name,
+                                                const char *message, zend_long code,
+                                                const char *file, zend_long line),
+                              void (*frame)(void *arg, const char *file, zend_long line,
+                                            const char *class_name, const char *function),
+                              void *arg) {
+    zval current;
+    int depth = 0;
+
+    ZVAL_COPY_VALUE(&current, object);
+    /* previous can't form a cycle, the depth is only a safety net */
+    while (Z_TYPE(current) == IS_OBJECT && depth++ < 64) {
+        zend_class_entry *base = zend_get_exception_base(PHPRS_OBJ(&current));
+        zval rv, *trace, *previous, *f;
+
+        exception(arg, ZSTR_VAL(Z_OBJCE(current)->name),
+                  phprs_property_string(base, &current, "message", sizeof("message")-1),
+                  phprs_property_long(base, &current, "code", sizeof("code")-1),
+                  phprs_property_string(base, &current, "file", sizeof("file")-1),
+                  phprs_property_long(base, &current, "line", sizeof("line")-1));
+
+        trace = zend_read_property(base, PHPRS_OBJ(&current), "trace", sizeof("trace")-1, 1, &rv);
+        if (Z_TYPE_P(trace) == IS_ARRAY) {
+            ZEND_HASH_FOREACH_VAL(Z_ARRVAL_P(trace), f) {
+                zval *line;
+
+                if (Z_TYPE_P(f) != IS_ARRAY) {
+                    continue;
+                }
+                line = zend_hash_str_find(Z_ARRVAL_P(f), "line", sizeof("line")-1);
+                frame(arg, phprs_array_string(Z_ARRVAL_P(f), "file"),
+                      line && Z_TYPE_P(line) == IS_LONG ? Z_LVAL_P(line) : 0,
+                      phprs_array_string(Z_ARRVAL_P(f), "class"),
+                      phprs_array_string(Z_ARRVAL_P(f), "function"));
+            } ZEND_HASH_FOREACH_END();
+        }
+
+        previous = zend_read_property(base, PHPRS_OBJ(&current), "previous",
+                                      sizeof("previous")-1, 1, &rv);
+        ZVAL_COPY_VALUE(&current, previous);
+    }
+}
+
+/*
+ * Functions implemented in Rust. They all share one handler that looks up the
+ * Rust side by the name it was called with, `data` is handed back to Rust
+ * untouched. Returning an exception for PHP to throw works like the pending
+ * flags, the handler throws it once Rust returned.
+ */
+
+typedef struct {
+    char *name;
+    void *data;
+} phprs_function;
+
+typedef struct {
+    char *parent;
+    char *name;
+} phprs_exception_class;
+
+static phprs_function *phprs_functions = NULL;
+static size_t phprs_function_count = 0;
+static phprs_exception_class *phprs_exception_classes = NULL;
+static size_t phprs_exception_class_count = 0;
+
+static void (*phprs_rust_call)(void *data, const char *name, zval *args, uint32_t argc,
+                               zval *return_value) = NULL;
+
+typedef struct {
+    char *class_name;
+    char *message;
+    zend_long code;
+} phprs_throw_t;
+
+#ifdef ZTS
+static TSRM_TLS phprs_throw_t phprs_pending_throw = {NULL, NULL, 0};
+#else
+static phprs_throw_t phprs_pending_throw = {NULL, NULL, 0};
+#endif
+
+/* Registers a function, it has to be called before the module starts. */
+void phprs_add_function(const char *name, void *data) {
+    phprs_functions = realloc(phprs_functions, (phprs_function_count + 1) * sizeof(phprs_function));
+    phprs_functions[phprs_function_count].name = strdup(name);
+    phprs_functions[phprs_function_count].data = data;
+    phprs_function_count++;
+}
+
+/*
+ * Registers an exception class extending `parent`, it has to be called before
+ * the module starts.
+ */
+void phprs_add_exception_class(const char *name, const char *parent) {
+    phprs_exception_classes = realloc(phprs_exception_classes,
+        (phprs_exception_class_count + 1) * sizeof(phprs_exception_class));
+    phprs_exception_classes[phprs_exception_class_count].name = strdup(name);
+    phprs_exception_classes[phprs_exception_class_count].parent = strdup(parent);
+    phprs_exception_class_count++;
+}
+
+void phprs_hook_functions(void (*cb)(void *data, const char *name, zval *args, uint32_t argc,
+                                     zval *return_value)) {
+    phprs_rust_call = cb;
+}
+
+/*
+ * Asks the handler to throw an exception once the Rust function returned.
+ * Copying may hit memory_limit, that bailout is left pending as well.
+ */
+void phprs_throw(const char *class_name, const char *message, size_t message_len,
+                 zend_long code) {
+    if (phprs_pending_throw.message) {
+        return;
+    }
+    zend_try {
+        phprs_pending_throw.class_name = estrdup(class_name);
+        phprs_pending_throw.message = estrndup(message, message_len);
+        phprs_pending_throw.code = code;
+    } zend_catch {
+        /* a copy that was made is freed with the request */
+        phprs_pending_throw.class_name = NULL;
+        phprs_pending_throw.message = NULL;
+        phprs_bailout();
+    } zend_end_try();
+}
+
+static void phprs_throw_pending() {
+    phprs_throw_t pending = phprs_pending_throw;
+    zend_string *name;
+    zend_class_entry *ce;
+
+    if (!pending.message) {
+        return;
+    }
+    /* reset first, looking up the class may bail out */
+    phprs_pending_throw.class_name = NULL;
+    phprs_pending_throw.message = NULL;
+
+    name = zend_string_init(pending.class_name, strlen(pending.class_name), 0);
+    /* may autoload the class, which is fine here but not from Rust */
+    ce = zend_lookup_class(name);
+    zend_string_release(name);
+    if (!ce || !instanceof_function(ce, zend_ce_throwable)
+            || (ce->ce_flags & (ZEND_ACC_INTERFACE | ZEND_ACC_EXPLICIT_ABSTRACT_CLASS))) {
+        zend_error(E_WARNING, "%s is not a Throwable class, throwing Exception instead",
+                   pending.class_name);
+        ce = zend_ce_exception;
+    }
+    zend_throw_exception(ce, pending.message, pending.code);
+    efree(pending.class_name);
+    efree(pending.message);
+}
+
+/* all Rust functions take any number of arguments */
+ZEND_BEGIN_ARG_INFO_EX(arginfo_phprs_rust_function, 0, 0, 0)
+    ZEND_ARG_VARIADIC_INFO(0, args)
+ZEND_END_ARG_INFO()
+
+static PHP_FUNCTION(phprs_rust_function) {
+    zval *args = NULL;
+    int argc = 0;
+    const char *name = ZSTR_VAL(EX(func)->common.function_name);
+    size_t i;
+
+    ZEND_PARSE_PARAMETERS_START(0, -1)
+        Z_PARAM_VARIADIC('*', args, argc)
+    ZEND_PARSE_PARAMETERS_END();
+
+    for (i = 0; i < phprs_function_count; i++) {
+        if (strcmp(phprs_functions[i].name, name) == 0) {
+            break;
+        }
+    }
+    if (i == phprs_function_count || !phprs_rust_call) {
+        return;
+    }
+    phprs_rust_call(phprs_functions[i].data, name, args, argc, return_value);
+    phprs_throw_pending();
+    phprs_handle_pending();
+}
+
+static zend_function_entry * phprs_function_entries() {
+    zend_function_entry *entries;
+    size_t i;
+
+    /* the Rust functions and the terminator */
+    entries = calloc(phprs_function_count + 1, sizeof(zend_function_entry));
+    for (i = 0; i < phprs_function_count; i++) {
+        entries[i].fname = phprs_functions[i].name;
+        entries[i].handler = ZEND_FN(phprs_rust_function);
+        entries[i].arg_info = arginfo_phprs_rust_function;
+        entries[i].num_args = 1;
+        entries[i].flags = ZEND_ACC_VARIADIC;
+    }
+    return entries;
+}
+
+static PHP_MINIT_FUNCTION(phprs) {
+    size_t i;
+
+    for (i = 0; i < phprs_exception_class_count; i++) {
+        phprs_exception_class *c = &phprs_exception_classes[i];
+        zend_class_entry ce, *parent;
+        char *lc_parent = zend_str_tolower_dup(c->parent, strlen(c->parent));
+
+        parent = zend_hash_str_find_ptr(CG(class_table), lc_parent, strlen(lc_parent));
+        efree(lc_parent);
+        if (!parent || !instanceof_function(parent, zend_ce_throwable)) {
+            zend_error(E_CORE_WARNING, "php-rs: %s can't extend %s, it is not a Throwable class",
+                       c->name, c->parent);
+            return FAILURE;
+        }
+        INIT_CLASS_ENTRY_EX(ce, c->name, strlen(c->name), NULL);
+        zend_register_internal_class_ex(&ce, parent);
+    }
+    return SUCCESS;
+}
+
+static zend_module_entry phprs_module_entry = {
+    STANDARD_MODULE_HEADER,
+    "php-rs",
+    NULL, /* set by phprs_module_startup */
+    PHP_MINIT(phprs),
+    NULL,
+    NULL,
+    NULL,
+    NULL,
+    NO_VERSION_YET,
+    STANDARD_MODULE_PROPERTIES
+};
+
+/* Z_TYPE_P and friends for the arguments of Rust functions */
+
+int phprs_zval_type(zval *zv) {
+    ZVAL_DEREF(zv);
+    return Z_TYPE_P(zv);
+}
+
+zend_long phprs_zval_long(zval *zv) {
+    ZVAL_DEREF(zv);
+    return Z_LVAL_P(zv);
+}
+
+double phprs_zval_double(zval *zv) {
+    ZVAL_DEREF(zv);
+    return Z_DVAL_P(zv);
+}
+
+const char * phprs_zval_string(zval *zv, size_t *len) {
+    ZVAL_DEREF(zv);
+    *len = Z_STRLEN_P(zv);
+    return Z_STRVAL_P(zv);
+}
+
+void phprs_zval_set_null(zval *zv) {
+    ZVAL_NULL(zv);
+}
+
+void phprs_zval_set_bool(zval *zv, int value) {
+    ZVAL_BOOL(zv, value);
+}
+
+void phprs_zval_set_long(zval *zv, zend_long value) {
+    ZVAL_LONG(zv, value);
+}
+
+void phprs_zval_set_double(zval *zv, double value) {
+    ZVAL_DOUBLE(zv, value);
+}
+
+/*
+ * Called from Rust, so hitting memory_limit leaves the bailout pending
+ * instead of jumping over the Rust frames. The zval stays undefined then.
+ */
+void phprs_zval_set_string(zval *zv, const char *value, size_t len) {
+    zend_try {
+        ZVAL_STRINGL(zv, value, len);
+    } zend_catch {
+        ZVAL_UNDEF(zv);
+        phprs_bailout();
+    } zend_end_try();
+}
+
+/*
+ * The zval, zend_string and HashTable API is mostly macros and static inline
+ * functions, which have no symbol bindgen could link against. These wrap the
+ * ones needed to work with values from Rust. Unlike the phprs_zval_* readers
+ * above they don't dereference references.
+ */
+
+zend_uchar phprs_z_type_p(const zval *zv) {
+    return Z_TYPE_P(zv);
+}
+
+zend_string * phprs_z_str_p(const zval *zv) {
+    return Z_STR_P(zv);
+}
+
+HashTable * phprs_z_arrval_p(const zval *zv) {
+    return Z_ARRVAL_P(zv);
+}
+
+zend_object * phprs_z_obj_p(const zval *zv) {
+    return Z_OBJ_P(zv);
+}
+
+zend_class_entry * phprs_z_objce_p(const zval *zv) {
+    return Z_OBJCE_P(zv);
+}
+
+zval * phprs_z_refval_p(zval *zv) {
+    return Z_REFVAL_P(zv);
+}
+
+void phprs_z_try_addref_p(zval *zv) {
+    Z_TRY_ADDREF_P(zv);
+}
+
+void phprs_zval_copy(zval *dst, const zval *src) {
+    ZVAL_COPY(dst, src);
+}
+
+void phprs_zval_set_str(zval *zv, zend_string *s) {
+    ZVAL_STR(zv, s);
+}
+
+void phprs_zval_set_array(zval *zv) {
+    array_init(zv);
+}
+
+char * phprs_zstr_val(zend_string *s) {
+    return ZSTR_VAL(s);
+}
+
+size_t phprs_zstr_len(const zend_string *s) {
+    return ZSTR_LEN(s);
+}
+
+zend_string * phprs_zstr_init(const char *str, size_t len, int persistent) {
+    return zend_string_init(str, len, persistent);
+}
+
+void phprs_zstr_release(zend_string *s) {
+    zend_string_release(s);
+}
+
+uint32_t phprs_hash_num_elements(const HashTable *ht) {
+    return zend_hash_num_elements(ht);
+}
+
+/*
+ * ZEND_HASH_FOREACH_KEY_VAL for Rust. `cb` gets the key as `h` for integer
+ * keys and as `key` for string keys, `key` is NULL for integer keys. Returning
+ * non-zero from `cb` stops the loop.
+ */
+void phprs_hash_foreach(HashTable *ht,
+                        int (*cb)(void *arg, zend_ulong h, zend_string *key, zval *val),
+                        void *arg) {
+    zend_ulong h;
+    zend_string *key;
+    zval *val;
+
+    ZEND_HASH_FOREACH_KEY_VAL(ht, h, key, val) {
+        if (cb(arg, h, key, val)) {
+            break;
+        }
+    } ZEND_HASH_FOREACH_END();
+}
+
+/*
+ * zend_try for Rust. Calls `cb` and returns 1 if it bailed out, 0 otherwise.
+ * The longjmp of a bailout skips the frames of `cb`, so nothing on them may
+ * need to be dropped: `cb` should only call into PHP, and Rust callers have
+ * to drop what they own before calling PHP functions that may bail out.
+ */
+int phprs_try(void (*cb)(void *arg), void *arg) {
+    volatile int bailed_out = 0;
+
+    zend_try {
+        cb(arg);
+    } zend_catch {
+        bailed_out = 1;
+    } zend_end_try();
+    return bailed_out;
+}
+
+int phprs_module_startup(sapi_module_struct *module) {
+    volatile int result = FAILURE;
+
+    phprs_module_entry.functions = phprs_function_entries();
+    zend_try {
+#if PHP_VERSION_ID >= 80200
+        result = php_module_startup(module, &phprs_module_entry);
+#else
+        result = php_module_startup(module, &phprs_module_entry, 1);
+#endif
+    } zend_end_try();
+    return result;
+}
+
+int phprs_request_startup() {
+    volatile int result = FAILURE;
+
+    zend_try {
+        result = php_request_startup();
+    } zend_end_try();
+    return result;
+}
+
+/*
+ * Opens and executes `filename` as the primary script. Returns 1 if it ran to
+ * its end and 0 if it bailed out. zend_file_handle changed a lot between
+ * versions, so it never leaves C.
+ */
+int phprs_execute_file(const char *filename) {
+    volatile int result = 0;
+    zend_file_handle file_handle;
+    FILE *fp = fopen(filename, "rb");
+
+#if PHP_VERSION_ID >= 70400
+    zend_stream_init_fp(&file_handle, fp, filename);
+#else
+    memset(&file_handle, 0, sizeof(file_handle));
+    file_handle.type = ZEND_HANDLE_FP;
+    file_handle.handle.fp = fp;
+    file_handle.filename = filename;
+#endif
+#if PHP_VERSION_ID >= 80100
+    file_handle.primary_script = 1;
+#endif
+
+    zend_try {
+        result = php_execute_script(&file_handle);
+    } zend_end_try();
+
+#if PHP_VERSION_ID >= 80100
+    zend_destroy_file_handle(&file_handle);
+#endif
+    return result;
+}
+
+/* Renames the running SAPI, sapi_startup copied the module struct. */
+void phprs_set_sapi_name(char *name) {
+    sapi_module.name = name;
+}
+
+/* mirrors phprs_opcache_status_t in lib.rs */
+typedef struct {
+    int enabled;
+    zend_long hits;
+    zend_long misses;
+    zend_long cached_scripts;
+    zend_long used_memory;
+    zend_long free_memory;
+    zend_long wasted_memory;
+} phprs_opcache_status_t;
+
+static zend_long phprs_array_long(HashTable *ht, const char *key) {
+    zval *zv = ht ? zend_hash_str_find(ht, key, strlen(key)) : NULL;
+
+    return zv ? zval_get_long(zv) : 0;
+}
+
+static HashTable * phprs_array_array(HashTable *ht, const char *key) {
+    zval *zv = zend_hash_str_find(ht, key, strlen(key));
+
+    return zv && Z_TYPE_P(zv) == IS_ARRAY ? Z_ARRVAL_P(zv) : NULL;
+}
+
+/*
+ * Fills `status` from opcache_get_status(false) in the current request.
+ * Returns 0 if OPcache is not loaded or not running.
+ */
+int phprs_opcache_status(phprs_opcache_status_t *status) {
+    volatile int result = 0;
+    zval function_name, retval, param;
+
+    if (!zend_hash_str_exists(EG(function_table), "opcache_get_status",
+                              sizeof("opcache_get_status")-1)) {
+        return 0;
+    }
+    ZVAL_STRING(&function_name, "opcache_get_status");
+    ZVAL_FALSE(&param);
+    ZVAL_UNDEF(&retval);
+    zend_try {
+        if (call_user_function(EG(function_table), NULL, &function_name, &retval,
+                               1, &param) == SUCCESS && Z_TYPE(retval) == IS_ARRAY) {
+            HashTable *ht = Z_ARRVAL(retval);
+            HashTable *memory = phprs_array_array(ht, "memory_usage");
+            HashTable *statistics = phprs_array_array(ht, "opcache_statistics");
+
+            status->enabled = phprs_array_long(ht, "opcache_enabled") != 0;
+            status->hits = phprs_array_long(statistics, "hits");
+            status->misses = phprs_array_long(statistics, "misses");
+            status->cached_scripts = phprs_array_long(statistics, "num_cached_scripts");
+            status->used_memory = phprs_array_long(memory, "used_memory");
+            status->free_memory = phprs_array_long(memory, "free_memory");
+            status->wasted_memory = phprs_array_long(memory, "wasted_memory");
+            result = 1;
+        }
+    } zend_end_try();
+    zval_ptr_dtor(&retval);
+    zval_ptr_dtor(&function_name);
+    return result;
+}
+
+void phprs_request_shutdown() {
+    phprs_remember_thrown(NULL);
+    zend_try {
+        php_request_shutdown(NULL);
+    } zend_end_try();
+    /* thrown while shutting down, the executor freed it with all objects */
+    phprs_last_thrown = NULL;
+}
+
+#define PHPRS_CALL_BAILOUT 1
+
+/*
+ * call_user_function for Rust. Returns SUCCESS or FAILURE like it does, or
+ * PHPRS_CALL_BAILOUT if the function hit a fatal error or called exit().
+ */
+int phprs_call_function(zval *function_name, zval *retval, uint32_t param_count, zval *params) {
+    volatile int result = FAILURE;
+
+    zend_try {
+        result = call_user_function(EG(function_table), NULL, function_name, retval,
+                                    param_count, params);
+    } zend_catch {
+        result = PHPRS_CALL_BAILOUT;
+    } zend_end_try();
+    return result;
+}
+
+#ifdef ZTS
 void zend_tsrmls_cache_update() {
     ZEND_TSRMLS_CACHE_UPDATE();
 }
+#endif
 
 /*
  * Create a wrapper around fopen
@@ -3,4 +3,14 @@
 #include <Zend/zend.h>
 #include <main/php.h>
 #include <sapi/embed/php_embed.h>
+#include <main/php_ini.h>
+#include <main/php_streams.h>
+#include <main/php_output.h>
+#include <Zend/zend_exceptions.h>
+#include <Zend/zend_interfaces.h>
+#include <Zend/zend_ini.h>
+#include <Zend/zend_compile.h>
+#include <Zend/zend_execute.h>
+#include <ext/standard/info.h>
+#include <ext/standard/php_var.h>
 #endif
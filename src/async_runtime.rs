@@ -0,0 +1,267 @@
+//! An async front end for the runtime pool, to be used from tokio.
+
+use std::cmp;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on;
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::io::AsyncRead;
+use tokio_util::io::ReaderStream;
+
+use super::{Callbacks, Error, Execution, Request, RuntimeBuilder, RuntimePool};
+
+/// Number of output chunks buffered before the php worker waits for the
+/// consumer.
+const OUTPUT_BUFFER: usize = 16;
+/// Number of body chunks read ahead of the php worker.
+const BODY_BUFFER: usize = 4;
+
+type BodyStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// A request for the `AsyncRuntime`.
+pub struct AsyncRequest {
+    request: Request,
+    body: Option<BodyStream>,
+}
+
+impl AsyncRequest {
+    /// Creates a request that executes the given php file with an empty body.
+    pub fn new(handle_filename: &str) -> Self {
+        AsyncRequest::from_request(Request::new(handle_filename).method("POST"))
+    }
+
+    /// Creates a request with an empty body from the given request
+    /// information.
+    pub fn from_request(request: Request) -> Self {
+        AsyncRequest {
+            request,
+            body: None,
+        }
+    }
+
+    /// Uses a fixed buffer as the body.
+    pub fn body<B: Into<Bytes>>(self, body: B) -> Self {
+        let body = body.into();
+        self.body_stream(futures::stream::once(async move { Ok(body) }))
+    }
+
+    /// Uses a stream of chunks as the body, it is read as PHP consumes
+    /// `php://input`.
+    pub fn body_stream<S>(mut self, body: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        self.body = Some(Box::pin(body));
+        self
+    }
+
+    /// Uses a reader as the body, it is read as PHP consumes `php://input`.
+    pub fn body_reader<R>(self, body: R) -> Self
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        self.body_stream(ReaderStream::new(body))
+    }
+}
+
+impl fmt::Debug for AsyncRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncRequest")
+            .field("request", &self.request)
+            .field("body", &self.body.is_some())
+            .finish()
+    }
+}
+
+/// The per request context of the `AsyncRuntime` workers.
+///
+/// Output is handed to the async side chunk by chunk and the body is pulled
+/// from it, the php worker blocks while either side is not ready.
+pub struct AsyncContext {
+    head: Option<oneshot::Sender<Head>>,
+    output: mpsc::Sender<Bytes>,
+    body: Option<mpsc::Receiver<io::Result<Bytes>>>,
+    pending: Bytes,
+}
+
+impl fmt::Debug for AsyncContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncContext")
+            .field("body", &self.body.is_some())
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl AsyncContext {
+    fn write(ctx: &mut AsyncContext, buf: &[u8]) -> Result<usize, ()> {
+        // A closed output means nobody listens any more, this aborts the
+        // request like a disconnected client.
+        block_on(ctx.output.send(Bytes::copy_from_slice(buf))).map_err(|_| ())?;
+        Ok(buf.len())
+    }
+
+    fn read(ctx: &mut AsyncContext, buf: *mut i8, bytes: usize) -> Result<usize, ()> {
+        if ctx.pending.is_empty() {
+            let next = match ctx.body {
+                Some(ref mut body) => block_on(body.next()),
+                None => None,
+            };
+            match next {
+                Some(Ok(chunk)) => ctx.pending = chunk,
+                Some(Err(_)) => return Err(()),
+                None => return Ok(0),
+            }
+        }
+        let copied = cmp::min(bytes, ctx.pending.len());
+        let chunk = ctx.pending.split_to(copied);
+        unsafe {
+            ptr::copy(chunk.as_ptr() as *const i8, buf, copied);
+        }
+        Ok(copied)
+    }
+
+    fn send_headers(ctx: &mut AsyncContext, status: u16, headers: &[&[u8]]) -> Result<(), ()> {
+        if let Some(head) = ctx.head.take() {
+            let headers = headers.iter().map(|h| h.to_vec()).collect();
+            head.send(Head { status, headers }).map_err(|_| ())?;
+        }
+        Ok(())
+    }
+
+    fn callbacks() -> Callbacks<AsyncContext> {
+        Callbacks::new()
+            .read(Box::new(AsyncContext::read))
+            .write(Box::new(AsyncContext::write))
+            .send_headers(Box::new(AsyncContext::send_headers))
+    }
+}
+
+/// The status and the raw header lines (`Name: value`) a script sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Head {
+    /// The response status.
+    pub status: u16,
+    /// The header lines including the default `Content-type`.
+    pub headers: Vec<Vec<u8>>,
+}
+
+/// The output of a request executed by the `AsyncRuntime`.
+///
+/// It is a stream of the chunks PHP writes. Dropping it before the end
+/// aborts the script the same way a disconnected client does.
+pub struct Output {
+    head: Option<oneshot::Receiver<Head>>,
+    chunks: mpsc::Receiver<Bytes>,
+    done: oneshot::Receiver<Result<Execution, Error>>,
+}
+
+impl Output {
+    /// Waits for the headers of the response. PHP always sends them before
+    /// the first output, so this does not need to wait for the body. It
+    /// returns `None` if the headers were already taken or the request died
+    /// before sending them.
+    pub async fn head(&mut self) -> Option<Head> {
+        match self.head.take() {
+            Some(head) => head.await.ok(),
+            None => None,
+        }
+    }
+
+    /// Waits for the script to finish, output that was not consumed yet is
+    /// discarded.
+    pub async fn finish(mut self) -> Result<Execution, Error> {
+        while self.chunks.next().await.is_some() {}
+        self.done.await.unwrap_or(Err(Error::Unavailable))
+    }
+
+    /// Polls for the result of the script, once the output is consumed it
+    /// is there without waiting.
+    pub(crate) fn poll_result(&mut self, cx: &mut Context) -> Poll<Result<Execution, Error>> {
+        Pin::new(&mut self.done)
+            .poll(cx)
+            .map(|result| result.unwrap_or(Err(Error::Unavailable)))
+    }
+}
+
+impl Stream for Output {
+    type Item = Bytes;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Bytes>> {
+        Pin::new(&mut self.chunks).poll_next(cx)
+    }
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Output").finish()
+    }
+}
+
+/// A runtime that executes requests on a pool of blocking PHP workers without
+/// blocking the async executor.
+///
+/// It has to be used from within a tokio runtime. Dropping the last clone
+/// shuts the pool down, which waits for the queued requests and joins the
+/// worker threads on the dropping thread. Drop it outside of the tokio
+/// runtime or in `tokio::task::spawn_blocking` to keep that off an executor
+/// thread.
+#[derive(Clone)]
+pub struct AsyncRuntime {
+    pool: Arc<RuntimePool<AsyncContext>>,
+}
+
+impl AsyncRuntime {
+    /// Starts the runtime with one worker per thread passed to
+    /// `Runtime::new`. The callbacks of the builder are replaced by the ones
+    /// talking to the async side.
+    pub fn new(builder: RuntimeBuilder<AsyncContext>) -> Self {
+        AsyncRuntime {
+            pool: Arc::new(builder.start_pool(|_| AsyncContext::callbacks())),
+        }
+    }
+
+    /// Dispatches a request to the next free worker. The output can be
+    /// consumed as it is produced.
+    pub async fn execute(&self, request: AsyncRequest) -> Output {
+        let (head_tx, head) = oneshot::channel();
+        let (output, chunks) = mpsc::channel(OUTPUT_BUFFER);
+        let (done_tx, done) = oneshot::channel();
+        let body = request.body.map(|stream| {
+            let (sender, receiver) = mpsc::channel(BODY_BUFFER);
+            tokio::spawn(async move {
+                let _ = stream.map(Ok).forward(sender).await;
+            });
+            receiver
+        });
+        let context = AsyncContext {
+            head: Some(head_tx),
+            output,
+            body,
+            pending: Bytes::new(),
+        };
+        self.pool
+            .spawn_request(request.request, context, move |_context, result| {
+                let _ = done_tx.send(result);
+            });
+        Output {
+            head: Some(head),
+            chunks,
+            done,
+        }
+    }
+}
+
+impl fmt::Debug for AsyncRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AsyncRuntime").finish()
+    }
+}
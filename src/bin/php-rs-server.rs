@@ -0,0 +1,261 @@
+//! A HTTP/1.1 server serving a PHP document root with the embedded runtime.
+//!
+//! ```text
+//! php-rs-server --root ./public --listen 127.0.0.1:8080
+//! curl http://127.0.0.1:8080/
+//! ```
+//!
+//! With `--fastcgi` it is a FastCGI responder instead that can replace
+//! php-fpm behind a web server.
+
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::Request;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+use php::{AsyncRuntime, FastCgiServer, PhpService, Runtime};
+
+const USAGE: &str = "Usage: php-rs-server [options]
+
+Options:
+    --listen <addr>      address to listen on (default: 127.0.0.1:8080)
+    --root <dir>         document root (default: the current directory)
+    --index <script>     front controller, relative to the document root
+                         (default: index.php)
+    --workers <n>        number of php worker threads (default: number of cpus,
+                         1 without ZTS)
+    --no-static          hand requests for non php files to the front
+                         controller instead of serving them
+    --fastcgi <addr>     speak FastCGI instead of HTTP on a TCP address or
+                         on a unix socket given as unix:<path>, the web
+                         server picks the script, --root and --index are
+                         not used
+    -h, --help           print this help";
+
+#[derive(Debug, PartialEq)]
+struct Config {
+    listen: SocketAddr,
+    root: PathBuf,
+    index: String,
+    workers: usize,
+    static_files: bool,
+    fastcgi: Option<String>,
+}
+
+impl Config {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config {
+            listen: "127.0.0.1:8080".parse().unwrap(),
+            root: PathBuf::from("."),
+            index: "index.php".to_owned(),
+            // PHP without ZTS only runs on one thread
+            workers: if cfg!(php_zts) {
+                thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            } else {
+                1
+            },
+            static_files: true,
+            fastcgi: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value =
+                |name: &str| args.next().ok_or_else(|| format!("{} needs a value", name));
+            match arg.as_str() {
+                "--listen" => {
+                    let listen = value("--listen")?;
+                    config.listen = listen
+                        .parse()
+                        .map_err(|e| format!("invalid address {}: {}", listen, e))?;
+                }
+                "--root" => config.root = PathBuf::from(value("--root")?),
+                "--index" => config.index = value("--index")?,
+                "--workers" => {
+                    let workers = value("--workers")?;
+                    config.workers = workers
+                        .parse()
+                        .map_err(|e| format!("invalid worker count {}: {}", workers, e))?;
+                    if config.workers == 0 {
+                        return Err("--workers needs at least one worker".to_owned());
+                    }
+                    if config.workers > 1 && !cfg!(php_zts) {
+                        return Err(format!(
+                            "--workers {} needs PHP built with ZTS, without it one worker runs",
+                            config.workers
+                        ));
+                    }
+                }
+                "--no-static" => config.static_files = false,
+                "--fastcgi" => config.fastcgi = Some(value("--fastcgi")?),
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                other => return Err(format!("unknown option {}\n\n{}", other, USAGE)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+    match config.fastcgi {
+        Some(ref listen) => serve_fastcgi(&config, listen),
+        None => serve_http(&config),
+    }
+}
+
+fn serve_fastcgi(config: &Config, listen: &str) {
+    let server = FastCgiServer::new(Runtime::new(
+        "php-rs-server",
+        "PHP Rust Server",
+        config.workers,
+    ))
+    .on_error(Box::new(|e| eprintln!("FastCGI connection failed: {}", e)));
+    eprintln!(
+        "serving FastCGI on {} with {} workers",
+        listen, config.workers
+    );
+    let result = if listen.starts_with("unix:") {
+        serve_unix(&server, &listen["unix:".len()..])
+    } else {
+        server.serve_tcp(listen)
+    };
+    if let Err(e) = result {
+        eprintln!("failed to serve FastCGI on {}: {}", listen, e);
+        process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix(server: &FastCgiServer, path: &str) -> std::io::Result<()> {
+    server.serve_unix(path)
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_server: &FastCgiServer, _path: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "unix sockets are not supported on this platform",
+    ))
+}
+
+fn serve_http(config: &Config) {
+    let root = match config.root.canonicalize() {
+        Ok(root) => root,
+        Err(e) => {
+            eprintln!("invalid document root {}: {}", config.root.display(), e);
+            process::exit(2);
+        }
+    };
+    let tokio = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start tokio");
+    tokio.block_on(async {
+        let runtime = AsyncRuntime::new(Runtime::new(
+            "php-rs-server",
+            "PHP Rust Server",
+            config.workers,
+        ));
+        let service = PhpService::new(runtime, &root, &config.index)
+            .static_files(config.static_files)
+            .on_error(Box::new(|e| eprintln!("request failed: {}", e)));
+
+        let listener = match TcpListener::bind(config.listen).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("failed to listen on {}: {}", config.listen, e);
+                process::exit(1);
+            }
+        };
+        eprintln!(
+            "serving {} on http://{} with {} workers",
+            root.display(),
+            config.listen,
+            config.workers
+        );
+
+        loop {
+            let (stream, remote) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let service = service.clone();
+            tokio::spawn(async move {
+                let handler = service_fn(move |mut request: Request<Incoming>| {
+                    let service = service.clone();
+                    request.extensions_mut().insert(remote);
+                    async move { Ok::<_, Infallible>(service.handle(request).await) }
+                });
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), handler)
+                    .await
+                {
+                    eprintln!("error serving {}: {}", remote, e);
+                }
+            });
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        let workers = if cfg!(php_zts) { 3 } else { 1 };
+        let config = parse(&[
+            "--listen",
+            "0.0.0.0:9000",
+            "--root",
+            "public",
+            "--index",
+            "app.php",
+            "--workers",
+            &workers.to_string(),
+            "--no-static",
+            "--fastcgi",
+            "unix:/run/php.sock",
+        ])
+        .unwrap();
+        assert_eq!(
+            config,
+            Config {
+                listen: "0.0.0.0:9000".parse().unwrap(),
+                root: PathBuf::from("public"),
+                index: "app.php".to_owned(),
+                workers,
+                static_files: false,
+                fastcgi: Some("unix:/run/php.sock".to_owned()),
+            }
+        );
+        assert!(parse(&["--workers"]).is_err());
+        assert!(parse(&["--workers", "0"]).is_err());
+        assert_eq!(parse(&["--workers", "2"]).is_ok(), cfg!(php_zts));
+        assert!(parse(&["--listen", "nope"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+//! A stand in for php-cli built on the embedded runtime, for images that only
+//! ship libphp.
+//!
+//! ```text
+//! php-rs run maintenance.php --dry-run
+//! php-rs repl
+//! ```
+
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+
+use php::{EvalError, Request, Runtime};
+
+const USAGE: &str = "Usage: php-rs <command> [options]
+
+Commands:
+    run [-d name=value]... <script> [args]...
+                         run a script like php-cli does, php-rs exits with
+                         the exit() status of the script
+    repl [-d name=value]...
+                         evaluate lines read from stdin in a single request,
+                         the values of expressions are printed, exit, quit
+                         or exit() ends the session
+
+Options:
+    -d name=value        set an ini directive
+    -h, --help           print this help";
+
+/// The ini directives php-cli hard codes.
+const CLI_INI: &[(&str, &str)] = &[
+    ("display_errors", "1"),
+    ("html_errors", "0"),
+    ("implicit_flush", "1"),
+    ("output_buffering", "0"),
+    ("max_execution_time", "0"),
+    ("max_input_time", "-1"),
+    ("register_argc_argv", "1"),
+];
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run {
+        ini: Vec<(String, String)>,
+        script: String,
+        args: Vec<String>,
+    },
+    Repl {
+        ini: Vec<(String, String)>,
+    },
+}
+
+impl Command {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+        match args.next().as_deref() {
+            Some("run") => Command::parse_run(args),
+            Some("repl") => Command::parse_repl(args),
+            Some("-h") | Some("--help") | None => Err(USAGE.to_owned()),
+            Some(other) => Err(format!("unknown command {}\n\n{}", other, USAGE)),
+        }
+    }
+
+    fn parse_run<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+        let mut ini = Vec::new();
+        while let Some(arg) = args.next() {
+            if !parse_option(&arg, &mut args, &mut ini)? {
+                // everything after the script belongs to the script
+                return Ok(Command::Run {
+                    ini,
+                    script: arg,
+                    args: args.collect(),
+                });
+            }
+        }
+        Err(format!("run needs a script\n\n{}", USAGE))
+    }
+
+    fn parse_repl<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+        let mut ini = Vec::new();
+        while let Some(arg) = args.next() {
+            if !parse_option(&arg, &mut args, &mut ini)? {
+                return Err(format!("unknown option {}\n\n{}", arg, USAGE));
+            }
+        }
+        Ok(Command::Repl { ini })
+    }
+}
+
+/// Parses the options shared by all commands, returns false if `arg` is not
+/// one of them.
+fn parse_option<I: Iterator<Item = String>>(
+    arg: &str,
+    args: &mut I,
+    ini: &mut Vec<(String, String)>,
+) -> Result<bool, String> {
+    match arg {
+        "-d" => {
+            let directive = args.next().ok_or("-d needs a value")?;
+            // like php-cli a bare name switches the directive on
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().to_owned();
+            let value = parts.next().unwrap_or("1").to_owned();
+            ini.push((name, value));
+            Ok(true)
+        }
+        "-h" | "--help" => Err(USAGE.to_owned()),
+        _ => Ok(false),
+    }
+}
+
+fn main() {
+    let command = match Command::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}", message);
+            process::exit(2);
+        }
+    };
+    match command {
+        Command::Run { ini, script, args } => process::exit(run(&ini, &script, &args)),
+        Command::Repl { ini } => process::exit(repl(&ini)),
+    }
+}
+
+fn runtime(ini: &[(String, String)]) -> Runtime<()> {
+    // Named like PHP's own CLI so `PHP_SAPI` is "cli" and scripts behave the
+    // way they do under `php`.
+    let mut builder = Runtime::new("cli", "PHP Rust CLI", 1).write(Box::new(write_stdout));
+    for (name, value) in CLI_INI {
+        builder = builder.ini(name, value);
+    }
+    for (name, value) in ini {
+        builder = builder.ini(name, value);
+    }
+    builder.start()
+}
+
+fn run(ini: &[(String, String)], script: &str, args: &[String]) -> i32 {
+    if !Path::new(script).is_file() {
+        eprintln!("Could not open input file: {}", script);
+        return 1;
+    }
+    let mut runtime = runtime(ini);
+    let request = Request::new(script).args(args).cli();
+    match runtime.execute_request(&request, &mut ()) {
+        Ok(execution) => execution.exit_status(),
+        Err(error) => error.exit_status(),
+    }
+}
+
+fn repl(ini: &[(String, String)]) -> i32 {
+    let mut runtime = runtime(ini);
+    let request = Request::new("").cli();
+    let mut context = ();
+    let mut session = match runtime.session(&request, &mut context) {
+        Ok(session) => session,
+        Err(error) => {
+            eprintln!("{}", error);
+            return error.exit_status();
+        }
+    };
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let mut code = String::new();
+    loop {
+        print!("{}", if code.is_empty() { "php > " } else { "... > " });
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if code.is_empty() && matches!(line.trim(), "exit" | "quit") {
+            break;
+        }
+        code.push_str(&line);
+        code.push('\n');
+        if code.trim().is_empty() {
+            code.clear();
+            continue;
+        }
+        match session.eval(&code) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => (),
+            // an unfinished block, keep reading
+            Err(EvalError::Parse(ref message)) if message.contains("unexpected end of file") => {
+                continue
+            }
+            Err(EvalError::Parse(message)) => eprintln!("Parse error: {}", message),
+            Err(EvalError::Exit(status)) => return status,
+            // the error was already printed
+            Err(EvalError::Fatal(_)) => (),
+        }
+        code.clear();
+    }
+    0
+}
+
+fn write_stdout(_: &mut (), buf: &[u8]) -> Result<usize, ()> {
+    let mut stdout = io::stdout();
+    // flushed right away so output interleaves with writes to STDOUT and
+    // STDERR from the script
+    stdout
+        .write_all(buf)
+        .and_then(|_| stdout.flush())
+        .map_err(|_| ())?;
+    Ok(buf.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        Command::parse(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(&[
+                "run",
+                "-d",
+                "memory_limit=1G",
+                "-d",
+                "assert.active",
+                "job.php",
+                "-d",
+                "x"
+            ])
+            .unwrap(),
+            Command::Run {
+                ini: vec![
+                    ("memory_limit".to_owned(), "1G".to_owned()),
+                    ("assert.active".to_owned(), "1".to_owned()),
+                ],
+                script: "job.php".to_owned(),
+                args: vec!["-d".to_owned(), "x".to_owned()],
+            }
+        );
+        assert_eq!(
+            parse(&["repl", "-d", "display_errors=0"]).unwrap(),
+            Command::Repl {
+                ini: vec![("display_errors".to_owned(), "0".to_owned())],
+            }
+        );
+        assert!(parse(&["repl", "script.php"]).is_err());
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["run"]).is_err());
+        assert!(parse(&["run", "-d"]).is_err());
+        assert!(parse(&["frobnicate"]).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+//! Warnings, notices and errors PHP reports while running a script.
+
+/// The level of a diagnostic, one per `E_*` constant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Level {
+    /// `E_ERROR`
+    Error,
+    /// `E_WARNING`
+    Warning,
+    /// `E_PARSE`
+    Parse,
+    /// `E_NOTICE`
+    Notice,
+    /// `E_CORE_ERROR`
+    CoreError,
+    /// `E_CORE_WARNING`
+    CoreWarning,
+    /// `E_COMPILE_ERROR`
+    CompileError,
+    /// `E_COMPILE_WARNING`
+    CompileWarning,
+    /// `E_USER_ERROR`
+    UserError,
+    /// `E_USER_WARNING`
+    UserWarning,
+    /// `E_USER_NOTICE`
+    UserNotice,
+    /// `E_STRICT`
+    Strict,
+    /// `E_RECOVERABLE_ERROR`
+    RecoverableError,
+    /// `E_DEPRECATED`
+    Deprecated,
+    /// `E_USER_DEPRECATED`
+    UserDeprecated,
+}
+
+impl Level {
+    /// The level of an `E_*` value, flags PHP adds internally are ignored.
+    pub fn from_type(error_type: i32) -> Option<Level> {
+        let level = match error_type & 0x7fff {
+            1 => Level::Error,
+            2 => Level::Warning,
+            4 => Level::Parse,
+            8 => Level::Notice,
+            16 => Level::CoreError,
+            32 => Level::CoreWarning,
+            64 => Level::CompileError,
+            128 => Level::CompileWarning,
+            256 => Level::UserError,
+            512 => Level::UserWarning,
+            1024 => Level::UserNotice,
+            2048 => Level::Strict,
+            4096 => Level::RecoverableError,
+            8192 => Level::Deprecated,
+            16384 => Level::UserDeprecated,
+            _ => return None,
+        };
+        Some(level)
+    }
+
+    /// Whether PHP stops the script after reporting it.
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            Level::Error
+            | Level::Parse
+            | Level::CoreError
+            | Level::CompileError
+            | Level::UserError
+            | Level::RecoverableError => true,
+            _ => false,
+        }
+    }
+
+    /// The name PHP prints in front of the message, e.g. `Warning`.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Level::Error | Level::CoreError | Level::CompileError | Level::UserError => {
+                "Fatal error"
+            }
+            Level::RecoverableError => "Recoverable fatal error",
+            Level::Warning | Level::CoreWarning | Level::CompileWarning | Level::UserWarning => {
+                "Warning"
+            }
+            Level::Parse => "Parse error",
+            Level::Notice | Level::UserNotice => "Notice",
+            Level::Strict => "Strict Standards",
+            Level::Deprecated | Level::UserDeprecated => "Deprecated",
+        }
+    }
+}
+
+/// A warning, notice or error PHP reported.
+///
+/// Errors a user error handler dealt with are not reported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// The level of the diagnostic.
+    pub level: Level,
+    /// The message without the level, file and line.
+    pub message: String,
+    /// The file it was reported for, empty if there is none.
+    pub file: String,
+    /// The line it was reported for, 0 if there is none.
+    pub line: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        assert_eq!(Level::from_type(2), Some(Level::Warning));
+        assert_eq!(Level::from_type(8192), Some(Level::Deprecated));
+        // E_DONT_BAIL
+        assert_eq!(Level::from_type(1 << 15 | 64), Some(Level::CompileError));
+        assert_eq!(Level::from_type(3), None);
+        assert!(Level::CompileError.is_fatal());
+        assert!(!Level::Deprecated.is_fatal());
+        assert_eq!(Level::UserError.name(), "Fatal error");
+        assert_eq!(Level::Notice.name(), "Notice");
+    }
+}
@@ -0,0 +1,239 @@
+//! The outcome of executing a request.
+
+use std::any::Any;
+use std::error;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+
+use super::{Diagnostic, Exception};
+
+/// How a request that was not stopped by an error ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+    /// The script ran to its end.
+    Completed,
+    /// The script called `exit()` or `die()`, with the exit status. A
+    /// `die("message")` exits with 0.
+    Exit(i32),
+    /// The client went away and PHP stopped the script, see
+    /// `RuntimeBuilder::aborted`. With `ignore_user_abort` set the script runs
+    /// to its end instead.
+    Aborted,
+}
+
+/// The result of a request that was not stopped by an error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    /// How the script ended.
+    pub termination: Termination,
+    /// The warnings, notices and deprecations PHP reported, in order.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Execution {
+    /// The exit status the request ended with, like php-cli reports it.
+    pub fn exit_status(&self) -> i32 {
+        match self.termination {
+            Termination::Completed | Termination::Aborted => 0,
+            Termination::Exit(status) => status,
+        }
+    }
+}
+
+/// Why a request did not run to its end.
+///
+/// The errors that stopped a running script carry the diagnostics PHP
+/// reported before, which often explain the error.
+#[derive(Debug)]
+pub enum Error {
+    /// A fatal error stopped the script. PHP already reported it through
+    /// the output if `display_errors` is on.
+    Fatal {
+        /// The error message.
+        message: String,
+        /// The file the error occurred in.
+        file: String,
+        /// The line the error occurred on.
+        line: u32,
+        /// The diagnostics reported before the error, in order.
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// An exception was thrown and nothing caught it. PHP already reported it
+    /// as a fatal error through the output if `display_errors` is on.
+    UncaughtException {
+        /// The exception.
+        exception: Exception,
+        /// The diagnostics reported before it was thrown, in order.
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// PHP bailed out without a fatal error to report, e.g. while starting
+    /// the request.
+    Bailout,
+    /// A callback panicked. The request was stopped as soon as the callback
+    /// returned to PHP.
+    Panic {
+        /// The panic payload.
+        payload: Box<dyn Any + Send>,
+        /// The diagnostics reported before the panic, in order.
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// The request could not be handed to a worker because the pool is shut
+    /// down, or the worker died while running it.
+    Unavailable,
+}
+
+impl Error {
+    /// The exit status php-cli reports for this error.
+    pub fn exit_status(&self) -> i32 {
+        255
+    }
+
+    /// The diagnostics PHP reported before the error, empty for errors that
+    /// did not stop a running script.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match *self {
+            Error::Fatal {
+                ref diagnostics, ..
+            }
+            | Error::UncaughtException {
+                ref diagnostics, ..
+            }
+            | Error::Panic {
+                ref diagnostics, ..
+            } => diagnostics,
+            Error::Bailout | Error::Unavailable => &[],
+        }
+    }
+
+    /// A panic with no diagnostics yet.
+    pub(crate) fn panic(payload: Box<dyn Any + Send>) -> Error {
+        Error::Panic {
+            payload,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    /// Attaches the diagnostics of the request to the error.
+    pub(crate) fn with_diagnostics(mut self, reported: Vec<Diagnostic>) -> Error {
+        match self {
+            Error::Fatal {
+                ref mut diagnostics,
+                ..
+            }
+            | Error::UncaughtException {
+                ref mut diagnostics,
+                ..
+            }
+            | Error::Panic {
+                ref mut diagnostics,
+                ..
+            } => *diagnostics = reported,
+            Error::Bailout | Error::Unavailable => (),
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Fatal {
+                ref message,
+                ref file,
+                line,
+                ..
+            } => write!(
+                f,
+                "PHP Fatal error: {} in {} on line {}",
+                message, file, line
+            ),
+            Error::UncaughtException { ref exception, .. } => {
+                write!(f, "PHP Fatal error: Uncaught {}", exception)
+            }
+            Error::Bailout => write!(f, "php bailed out"),
+            Error::Panic { ref payload, .. } => match panic_message(&**payload) {
+                Some(message) => write!(f, "a php callback panicked: {}", message),
+                None => write!(f, "a php callback panicked"),
+            },
+            Error::Unavailable => write!(f, "no php worker is available"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// The message of a panic payload, if it has one.
+fn panic_message(payload: &(dyn Any + Send)) -> Option<&str> {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        Some(message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        Some(message)
+    } else {
+        None
+    }
+}
+
+/// After a bailout, returns the fatal error that caused it or `None` if it was
+/// `exit()`. Has to be called before the request is shut down.
+pub(crate) unsafe fn last_fatal_error() -> Option<Error> {
+    if php_sys::phprs_last_error_is_fatal() == 0 {
+        return None;
+    }
+    Some(Error::Fatal {
+        message: string(php_sys::pg_last_error_message()),
+        file: string(php_sys::pg_last_error_file()),
+        line: php_sys::pg_last_error_lineno() as u32,
+        diagnostics: Vec::new(),
+    })
+}
+
+unsafe fn string(s: *const c_char) -> String {
+    if s.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Level;
+    use super::*;
+
+    #[test]
+    fn test_exit_status() {
+        let execution = |termination| Execution {
+            termination,
+            diagnostics: Vec::new(),
+        };
+        assert_eq!(execution(Termination::Completed).exit_status(), 0);
+        assert_eq!(execution(Termination::Exit(3)).exit_status(), 3);
+        let warning = Diagnostic {
+            level: Level::Warning,
+            message: "Undefined variable: rows".to_owned(),
+            file: "/srv/job.php".to_owned(),
+            line: 9,
+        };
+        let fatal = Error::Fatal {
+            message: "Allowed memory size exhausted".to_owned(),
+            file: "/srv/job.php".to_owned(),
+            line: 12,
+            diagnostics: Vec::new(),
+        }
+        .with_diagnostics(vec![warning.clone()]);
+        assert_eq!(fatal.diagnostics(), &[warning][..]);
+        assert_eq!(fatal.exit_status(), 255);
+        assert_eq!(
+            fatal.to_string(),
+            "PHP Fatal error: Allowed memory size exhausted in /srv/job.php on line 12"
+        );
+        let panic = Error::panic(Box::new("broken pipe"));
+        assert_eq!(panic.exit_status(), 255);
+        assert_eq!(panic.to_string(), "a php callback panicked: broken pipe");
+        assert_eq!(
+            Error::panic(Box::new(42)).to_string(),
+            "a php callback panicked"
+        );
+    }
+}
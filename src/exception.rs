@@ -0,0 +1,163 @@
+//! Uncaught PHP exceptions.
+
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_void};
+
+/// A PHP exception or error nothing caught.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exception {
+    /// The class of the exception, e.g. `InvalidArgumentException`.
+    pub class: String,
+    /// The message passed to the constructor.
+    pub message: String,
+    /// The code passed to the constructor.
+    pub code: i64,
+    /// The file the exception was created in.
+    pub file: String,
+    /// The line the exception was created on.
+    pub line: u32,
+    /// The frames of `getTrace()`, innermost first.
+    pub trace: Vec<Frame>,
+    /// The exception returned by `getPrevious()`.
+    pub previous: Option<Box<Exception>>,
+}
+
+/// A frame of an exception's stack trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    /// The function that was called, e.g. `handle` or `{closure}`.
+    pub function: String,
+    /// The class of the method that was called, if it was one.
+    pub class: Option<String>,
+    /// The file of the call, `None` for calls made by PHP itself.
+    pub file: Option<String>,
+    /// The line of the call, `None` for calls made by PHP itself.
+    pub line: Option<u32>,
+}
+
+impl Exception {
+    /// The exception and the ones chained to it by `getPrevious()`, this one
+    /// first.
+    pub fn chain(&self) -> ExceptionChain {
+        ExceptionChain { next: Some(self) }
+    }
+
+    /// Describes the exception object `object` points to.
+    pub(crate) unsafe fn describe(object: *mut php_sys::zval) -> Exception {
+        let mut chain: Vec<Exception> = Vec::new();
+        php_sys::phprs_describe_exception(
+            object,
+            Some(collect_exception),
+            Some(collect_frame),
+            &mut chain as *mut Vec<Exception> as *mut c_void,
+        );
+        let mut exception = chain.pop().expect("an exception to describe");
+        while let Some(mut outer) = chain.pop() {
+            outer.previous = Some(Box::new(exception));
+            exception = outer;
+        }
+        exception
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} in {}:{}",
+            self.class, self.message, self.file, self.line
+        )
+    }
+}
+
+/// Iterates over an exception and the ones chained to it.
+#[derive(Debug)]
+pub struct ExceptionChain<'a> {
+    next: Option<&'a Exception>,
+}
+
+impl<'a> Iterator for ExceptionChain<'a> {
+    type Item = &'a Exception;
+
+    fn next(&mut self) -> Option<&'a Exception> {
+        let current = self.next?;
+        self.next = current.previous.as_ref().map(|previous| &**previous);
+        Some(current)
+    }
+}
+
+unsafe extern "C" fn collect_exception(
+    chain: *mut c_void,
+    class_name: *const c_char,
+    message: *const c_char,
+    code: php_sys::zend_long,
+    file: *const c_char,
+    line: php_sys::zend_long,
+) {
+    let chain = &mut *(chain as *mut Vec<Exception>);
+    chain.push(Exception {
+        class: string(class_name).unwrap_or_default(),
+        message: string(message).unwrap_or_default(),
+        code: code as i64,
+        file: string(file).unwrap_or_default(),
+        line: line as u32,
+        trace: Vec::new(),
+        previous: None,
+    });
+}
+
+unsafe extern "C" fn collect_frame(
+    chain: *mut c_void,
+    file: *const c_char,
+    line: php_sys::zend_long,
+    class_name: *const c_char,
+    function: *const c_char,
+) {
+    let chain = &mut *(chain as *mut Vec<Exception>);
+    if let Some(exception) = chain.last_mut() {
+        exception.trace.push(Frame {
+            function: string(function).unwrap_or_default(),
+            class: string(class_name),
+            file: string(file),
+            line: if line > 0 { Some(line as u32) } else { None },
+        });
+    }
+}
+
+unsafe fn string(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(s).to_string_lossy().into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exception(class: &str, message: &str, previous: Option<Exception>) -> Exception {
+        Exception {
+            class: class.to_owned(),
+            message: message.to_owned(),
+            code: 0,
+            file: "/srv/app.php".to_owned(),
+            line: 7,
+            trace: Vec::new(),
+            previous: previous.map(Box::new),
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let inner = exception("PDOException", "connection refused", None);
+        let outer = exception("RuntimeException", "failed to load user", Some(inner));
+        let classes: Vec<&str> = outer.chain().map(|e| e.class.as_str()).collect();
+        assert_eq!(classes, ["RuntimeException", "PDOException"]);
+        assert_eq!(
+            outer.to_string(),
+            "RuntimeException: failed to load user in /srv/app.php:7"
+        );
+    }
+}
@@ -0,0 +1,699 @@
+//! A FastCGI responder, so the runtime can sit behind a web server the same
+//! way php-fpm does.
+//!
+//! Every connection is handled on its own thread, the requests themselves run
+//! on a `RuntimePool`. `FCGI_PARAMS` end up in the request info and
+//! `$_SERVER`, `FCGI_STDIN` is streamed into the body PHP reads and the output
+//! is sent back as `FCGI_STDOUT` records, starting with a CGI style header
+//! block. Once the body is read the connection is still watched while the
+//! script runs, so an `FCGI_ABORT_REQUEST` or a closed connection shows up
+//! as an aborted request.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+
+use super::{Callbacks, Error, Execution, Request, RuntimeBuilder, RuntimePool};
+
+const VERSION_1: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+const UNKNOWN_TYPE: u8 = 11;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+
+const REQUEST_COMPLETE: u8 = 0;
+const UNKNOWN_ROLE: u8 = 3;
+
+/// Biggest content a single record can carry.
+const MAX_CONTENT: usize = 0xffff;
+
+/// A FastCGI record.
+#[derive(Debug, Clone, PartialEq)]
+struct Record {
+    kind: u8,
+    id: u16,
+    content: Vec<u8>,
+}
+
+/// Reads the next record, `None` if the peer closed the connection.
+fn read_record<R: Read>(reader: &mut R) -> io::Result<Option<Record>> {
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    if header[0] != VERSION_1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported FastCGI version {}", header[0]),
+        ));
+    }
+    let id = u16::from_be_bytes([header[2], header[3]]);
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding = header[6] as usize;
+    let mut content = vec![0u8; length + padding];
+    reader.read_exact(&mut content)?;
+    content.truncate(length);
+    Ok(Some(Record {
+        kind: header[1],
+        id,
+        content,
+    }))
+}
+
+/// Writes `content` as one or more records of the given type. An empty
+/// content writes a single empty record, which ends a stream.
+fn write_records<W: Write>(writer: &mut W, kind: u8, id: u16, content: &[u8]) -> io::Result<()> {
+    let mut chunks = content.chunks(MAX_CONTENT).peekable();
+    if chunks.peek().is_none() {
+        return write_record(writer, kind, id, &[]);
+    }
+    for chunk in chunks {
+        write_record(writer, kind, id, chunk)?;
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, kind: u8, id: u16, content: &[u8]) -> io::Result<()> {
+    let length = content.len() as u16;
+    let padding = (8 - content.len() % 8) % 8;
+    let id = id.to_be_bytes();
+    let length = length.to_be_bytes();
+    let header = [
+        VERSION_1,
+        kind,
+        id[0],
+        id[1],
+        length[0],
+        length[1],
+        padding as u8,
+        0,
+    ];
+    writer.write_all(&header)?;
+    writer.write_all(content)?;
+    writer.write_all(&[0u8; 8][..padding])?;
+    Ok(())
+}
+
+fn write_end_request<W: Write>(
+    writer: &mut W,
+    id: u16,
+    app_status: u32,
+    status: u8,
+) -> io::Result<()> {
+    let app_status = app_status.to_be_bytes();
+    let body = [
+        app_status[0],
+        app_status[1],
+        app_status[2],
+        app_status[3],
+        status,
+        0,
+        0,
+        0,
+    ];
+    write_record(writer, END_REQUEST, id, &body)?;
+    writer.flush()
+}
+
+/// Decodes FastCGI name-value pairs.
+fn decode_params(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    fn length(data: &mut &[u8]) -> io::Result<usize> {
+        match data.first() {
+            Some(&b) if b & 0x80 == 0 => {
+                *data = &data[1..];
+                Ok(b as usize)
+            }
+            Some(_) if data.len() >= 4 => {
+                let length = u32::from_be_bytes([data[0] & 0x7f, data[1], data[2], data[3]]);
+                *data = &data[4..];
+                Ok(length as usize)
+            }
+            _ => Err(invalid_params()),
+        }
+    }
+    let mut params = Vec::new();
+    while !data.is_empty() {
+        let name_length = length(&mut data)?;
+        let value_length = length(&mut data)?;
+        if data.len() < name_length + value_length {
+            return Err(invalid_params());
+        }
+        let name = String::from_utf8_lossy(&data[..name_length]).into_owned();
+        let value = String::from_utf8_lossy(&data[name_length..name_length + value_length]);
+        params.push((name, value.into_owned()));
+        data = &data[name_length + value_length..];
+    }
+    Ok(params)
+}
+
+/// Encodes FastCGI name-value pairs.
+fn encode_params<'a, I>(params: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (&'a str, &'a str)>,
+{
+    fn length(out: &mut Vec<u8>, length: usize) {
+        if length < 0x80 {
+            out.push(length as u8);
+        } else {
+            out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    let mut out = Vec::new();
+    for (name, value) in params {
+        length(&mut out, name.len());
+        length(&mut out, value.len());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+fn invalid_params() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed FastCGI params")
+}
+
+/// Builds the request information from the FastCGI params, every param is
+/// also passed on to `$_SERVER`.
+fn request_from_params(params: &[(String, String)]) -> Request {
+    let lookup: HashMap<&str, &str> = params
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    let script = match lookup.get("SCRIPT_FILENAME") {
+        Some(script) => (*script).to_owned(),
+        None => format!(
+            "{}{}",
+            lookup.get("DOCUMENT_ROOT").unwrap_or(&""),
+            lookup.get("SCRIPT_NAME").unwrap_or(&"")
+        ),
+    };
+    let mut request = Request::new(&script)
+        .method(lookup.get("REQUEST_METHOD").unwrap_or(&"GET"))
+        .uri(lookup.get("REQUEST_URI").unwrap_or(&"/"))
+        .query_string(lookup.get("QUERY_STRING").unwrap_or(&""));
+    if let Some(content_type) = lookup.get("CONTENT_TYPE").filter(|c| !c.is_empty()) {
+        request = request.content_type(content_type);
+    }
+    if let Some(length) = lookup.get("CONTENT_LENGTH").and_then(|l| l.parse().ok()) {
+        request = request.content_length(length);
+    }
+    if let Some(cookies) = lookup.get("HTTP_COOKIE") {
+        request = request.cookies(cookies);
+    }
+    for (name, value) in params {
+        request = request.server_var(name, value);
+    }
+    request
+}
+
+/// The per request context of the FastCGI workers.
+pub struct FastCgiContext {
+    id: u16,
+    output: Box<dyn Write + Send>,
+    body: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl fmt::Debug for FastCgiContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FastCgiContext")
+            .field("id", &self.id)
+            .field("pending", &self.pending.len())
+            .field("aborted", &self.aborted.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl FastCgiContext {
+    fn write(ctx: &mut FastCgiContext, buf: &[u8]) -> Result<usize, ()> {
+        write_records(&mut ctx.output, STDOUT, ctx.id, buf).map_err(|_| ())?;
+        Ok(buf.len())
+    }
+
+    fn read(ctx: &mut FastCgiContext, buf: *mut i8, bytes: usize) -> Result<usize, ()> {
+        if ctx.pending.is_empty() {
+            match ctx.body.recv() {
+                Ok(chunk) => ctx.pending = chunk,
+                Err(_) => return Ok(0),
+            }
+        }
+        let copied = cmp::min(bytes, ctx.pending.len());
+        unsafe {
+            ptr::copy(ctx.pending.as_ptr() as *const i8, buf, copied);
+        }
+        ctx.pending.drain(..copied);
+        Ok(copied)
+    }
+
+    fn send_headers(ctx: &mut FastCgiContext, status: u16, headers: &[&[u8]]) -> Result<(), ()> {
+        let mut block = format!("Status: {}\r\n", status).into_bytes();
+        for header in headers {
+            block.extend_from_slice(header);
+            block.extend_from_slice(b"\r\n");
+        }
+        block.extend_from_slice(b"\r\n");
+        write_records(&mut ctx.output, STDOUT, ctx.id, &block).map_err(|_| ())
+    }
+
+    fn aborted(ctx: &mut FastCgiContext) -> bool {
+        ctx.aborted.load(Ordering::SeqCst)
+    }
+
+    fn callbacks() -> Callbacks<FastCgiContext> {
+        Callbacks::new()
+            .read(Box::new(FastCgiContext::read))
+            .write(Box::new(FastCgiContext::write))
+            .send_headers(Box::new(FastCgiContext::send_headers))
+            .aborted(Box::new(FastCgiContext::aborted))
+    }
+}
+
+/// A connection the server can talk FastCGI over.
+trait Connection: Read + Write + Send + 'static {
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>>;
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>>;
+    fn close(&self);
+}
+
+impl Connection for TcpStream {
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn reader(&self) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn writer(&self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn close(&self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// What happens on a connection: a record came in or the running request
+/// finished.
+enum Event {
+    Record(io::Result<Option<Record>>),
+    Finished(Result<Execution, Error>),
+}
+
+/// Reads the records of a connection on a thread of its own, so the
+/// connection can be watched while a request runs.
+fn spawn_reader(mut reader: Box<dyn Read + Send>, events: mpsc::Sender<Event>) {
+    thread::spawn(move || loop {
+        let record = read_record(&mut reader);
+        let last = match record {
+            Ok(Some(_)) => false,
+            _ => true,
+        };
+        if events.send(Event::Record(record)).is_err() || last {
+            return;
+        }
+    });
+}
+
+/// The next record of the connection, `None` once it is closed.
+fn next_record(events: &mpsc::Receiver<Event>) -> io::Result<Option<Record>> {
+    loop {
+        match events.recv() {
+            Ok(Event::Record(record)) => return record,
+            Ok(Event::Finished(_)) => (),
+            Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// A FastCGI responder server running requests on a pool of PHP workers.
+#[derive(Clone)]
+pub struct FastCgiServer {
+    pool: Arc<RuntimePool<FastCgiContext>>,
+    error: Option<Arc<dyn Fn(&io::Error) + Send + Sync>>,
+}
+
+impl FastCgiServer {
+    /// Starts the server's worker pool with one worker per thread passed to
+    /// `Runtime::new`. The callbacks of the builder are replaced by the ones
+    /// talking FastCGI.
+    pub fn new(builder: RuntimeBuilder<FastCgiContext>) -> Self {
+        FastCgiServer {
+            pool: Arc::new(builder.start_pool(|_| FastCgiContext::callbacks())),
+            error: None,
+        }
+    }
+
+    /// Sets a hook that is told about connections failing, like a peer
+    /// sending malformed records. The connection is closed either way.
+    pub fn on_error(mut self, hook: Box<dyn Fn(&io::Error) + Send + Sync>) -> Self {
+        self.error = Some(Arc::from(hook));
+        self
+    }
+
+    /// Accepts FastCGI connections on a TCP socket, this does not return
+    /// unless listening fails.
+    pub fn serve_tcp<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        self.serve_tcp_listener(TcpListener::bind(addr)?)
+    }
+
+    /// Accepts FastCGI connections on a TCP socket that is already bound,
+    /// this does not return unless listening fails.
+    pub fn serve_tcp_listener(&self, listener: TcpListener) -> io::Result<()> {
+        for stream in listener.incoming() {
+            self.spawn_connection(stream?);
+        }
+        Ok(())
+    }
+
+    /// Accepts FastCGI connections on a unix socket, this does not return
+    /// unless listening fails. A stale socket file is replaced.
+    #[cfg(unix)]
+    pub fn serve_unix<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        for stream in listener.incoming() {
+            self.spawn_connection(stream?);
+        }
+        Ok(())
+    }
+
+    fn spawn_connection<C: Connection>(&self, mut connection: C) {
+        let server = self.clone();
+        thread::spawn(move || {
+            let result = server.handle_connection(&mut connection);
+            connection.close();
+            if let (Err(e), Some(hook)) = (result, server.error.as_ref()) {
+                hook(&e);
+            }
+        });
+    }
+
+    /// Handles the requests on a connection one after another.
+    fn handle_connection<C: Connection>(&self, connection: &mut C) -> io::Result<()> {
+        let (events, incoming) = mpsc::channel();
+        spawn_reader(connection.reader()?, events.clone());
+        loop {
+            let (id, keep_conn) = match self.begin_request(connection, &incoming)? {
+                Some(begin) => begin,
+                None => return Ok(()),
+            };
+            if !self.handle_request(connection, &incoming, &events, id)? || !keep_conn {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Waits for the next responder request, answering management records
+    /// on the way.
+    fn begin_request<C: Connection>(
+        &self,
+        connection: &mut C,
+        incoming: &mpsc::Receiver<Event>,
+    ) -> io::Result<Option<(u16, bool)>> {
+        loop {
+            let record = match next_record(incoming)? {
+                Some(record) => record,
+                None => return Ok(None),
+            };
+            match record.kind {
+                BEGIN_REQUEST if record.content.len() >= 3 => {
+                    let role = u16::from_be_bytes([record.content[0], record.content[1]]);
+                    let keep_conn = record.content[2] & KEEP_CONN != 0;
+                    if role == RESPONDER {
+                        return Ok(Some((record.id, keep_conn)));
+                    }
+                    write_end_request(connection, record.id, 0, UNKNOWN_ROLE)?;
+                }
+                GET_VALUES => {
+                    let wanted = decode_params(&record.content)?;
+                    let values: Vec<(&str, &str)> = wanted
+                        .iter()
+                        .filter_map(|(name, _)| match name.as_str() {
+                            "FCGI_MPXS_CONNS" => Some(("FCGI_MPXS_CONNS", "0")),
+                            _ => None,
+                        })
+                        .collect();
+                    write_records(connection, GET_VALUES_RESULT, 0, &encode_params(values))?;
+                    connection.flush()?;
+                }
+                // stray records of an aborted or finished request
+                ABORT_REQUEST | PARAMS | STDIN => (),
+                kind => {
+                    write_record(connection, UNKNOWN_TYPE, 0, &[kind, 0, 0, 0, 0, 0, 0, 0])?;
+                    connection.flush()?;
+                }
+            }
+        }
+    }
+
+    /// Reads the params and body of a request and runs it, `false` if the
+    /// connection was closed on the way.
+    fn handle_request<C: Connection>(
+        &self,
+        connection: &mut C,
+        incoming: &mpsc::Receiver<Event>,
+        events: &mpsc::Sender<Event>,
+        id: u16,
+    ) -> io::Result<bool> {
+        let mut params = Vec::new();
+        loop {
+            match next_record(incoming)? {
+                Some(ref record) if record.kind == PARAMS && record.id == id => {
+                    if record.content.is_empty() {
+                        break;
+                    }
+                    params.extend_from_slice(&record.content);
+                }
+                Some(ref record) if record.kind == ABORT_REQUEST && record.id == id => {
+                    write_end_request(connection, id, 0, REQUEST_COMPLETE)?;
+                    return Ok(true);
+                }
+                Some(_) => (),
+                None => return Ok(false),
+            }
+        }
+        let request = request_from_params(&decode_params(&params)?);
+
+        let (body, body_receiver) = mpsc::channel();
+        let done = events.clone();
+        let aborted = Arc::new(AtomicBool::new(false));
+        let context = FastCgiContext {
+            id,
+            output: connection.writer()?,
+            body: body_receiver,
+            pending: Vec::new(),
+            aborted: aborted.clone(),
+        };
+        self.pool
+            .spawn_request(request, context, move |_context, result| {
+                let _ = done.send(Event::Finished(result));
+            });
+
+        // Stream the body to the worker and keep watching the connection
+        // until the script is done.
+        let mut body = Some(body);
+        let mut open = true;
+        let mut failure = None;
+        let result = loop {
+            let record = match incoming.recv() {
+                Ok(Event::Finished(result)) => break result,
+                Ok(Event::Record(record)) => record,
+                Err(_) => break Err(Error::Unavailable),
+            };
+            match record {
+                Ok(Some(ref record)) if record.kind == STDIN && record.id == id => {
+                    if record.content.is_empty() {
+                        body = None;
+                    } else if let Some(ref sender) = body {
+                        // once the script is done, the rest of the body is dropped
+                        let _ = sender.send(record.content.clone());
+                    }
+                }
+                Ok(Some(ref record)) if record.kind == ABORT_REQUEST && record.id == id => {
+                    aborted.store(true, Ordering::SeqCst);
+                    body = None;
+                }
+                Ok(Some(_)) => (),
+                Ok(None) => {
+                    aborted.store(true, Ordering::SeqCst);
+                    body = None;
+                    open = false;
+                }
+                Err(e) => {
+                    aborted.store(true, Ordering::SeqCst);
+                    body = None;
+                    failure = Some(e);
+                }
+            }
+        };
+        drop(body);
+        if let Some(e) = failure {
+            return Err(e);
+        }
+        if !open {
+            return Ok(false);
+        }
+
+        write_records(connection, STDOUT, id, &[])?;
+        let app_status = match result {
+            Ok(execution) => execution.exit_status(),
+            Err(ref error) => error.exit_status(),
+        };
+        write_end_request(connection, id, app_status as u32, REQUEST_COMPLETE)?;
+        Ok(true)
+    }
+}
+
+impl fmt::Debug for FastCgiServer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FastCgiServer").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Encodes a request the way nginx sends it.
+    fn client_request(id: u16, params: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_record(
+            &mut out,
+            BEGIN_REQUEST,
+            id,
+            &[0, 1, KEEP_CONN, 0, 0, 0, 0, 0],
+        )
+        .unwrap();
+        write_records(&mut out, PARAMS, id, &encode_params(params.iter().cloned())).unwrap();
+        write_records(&mut out, PARAMS, id, &[]).unwrap();
+        write_records(&mut out, STDIN, id, body).unwrap();
+        write_records(&mut out, STDIN, id, &[]).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_records() {
+        let long = vec![7u8; MAX_CONTENT + 10];
+        let mut out = Vec::new();
+        write_records(&mut out, STDOUT, 3, b"hello").unwrap();
+        write_records(&mut out, STDOUT, 3, &long).unwrap();
+        write_records(&mut out, STDOUT, 3, &[]).unwrap();
+        assert_eq!(out.len() % 8, 0);
+
+        let mut reader = Cursor::new(out);
+        let first = read_record(&mut reader).unwrap().unwrap();
+        assert_eq!(
+            first,
+            Record {
+                kind: STDOUT,
+                id: 3,
+                content: b"hello".to_vec(),
+            }
+        );
+        assert_eq!(
+            read_record(&mut reader).unwrap().unwrap().content.len(),
+            MAX_CONTENT
+        );
+        assert_eq!(read_record(&mut reader).unwrap().unwrap().content.len(), 10);
+        assert!(read_record(&mut reader)
+            .unwrap()
+            .unwrap()
+            .content
+            .is_empty());
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_params() {
+        let long = "x".repeat(300);
+        let params = vec![
+            ("SCRIPT_FILENAME", "/srv/index.php"),
+            ("LONG", long.as_str()),
+        ];
+        let decoded = decode_params(&encode_params(params.clone())).unwrap();
+        let decoded: Vec<(&str, &str)> = decoded
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(decoded, params);
+        assert!(decode_params(&[5, 1, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_client_request() {
+        let data = client_request(
+            1,
+            &[
+                ("SCRIPT_FILENAME", "/srv/index.php"),
+                ("REQUEST_METHOD", "POST"),
+                ("QUERY_STRING", "a=1"),
+                ("CONTENT_LENGTH", "5"),
+                ("HTTP_COOKIE", "session=1"),
+            ],
+            b"hello",
+        );
+        let mut reader = Cursor::new(data);
+        let begin = read_record(&mut reader).unwrap().unwrap();
+        assert_eq!(begin.kind, BEGIN_REQUEST);
+        let params = read_record(&mut reader).unwrap().unwrap();
+        let request = request_from_params(&decode_params(&params.content).unwrap());
+        assert_eq!(request.script(), "/srv/index.php");
+        assert_eq!(
+            request.cookie_data().unwrap().to_str().unwrap(),
+            "session=1"
+        );
+        let vars: Vec<(String, String)> = request
+            .server_vars()
+            .into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v))
+            .collect();
+        assert!(vars.contains(&("REQUEST_METHOD".to_owned(), "POST".to_owned())));
+        assert!(vars.contains(&("CONTENT_LENGTH".to_owned(), "5".to_owned())));
+        assert!(vars.contains(&("HTTP_COOKIE".to_owned(), "session=1".to_owned())));
+        assert!(read_record(&mut reader)
+            .unwrap()
+            .unwrap()
+            .content
+            .is_empty());
+        assert_eq!(read_record(&mut reader).unwrap().unwrap().content, b"hello");
+    }
+}
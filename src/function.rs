@@ -0,0 +1,185 @@
+//! PHP functions implemented in Rust.
+
+use std::error;
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::{c_char, c_int};
+use std::slice;
+
+/// A value passed to or returned from a Rust function.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// `null`
+    Null,
+    /// `true` or `false`
+    Bool(bool),
+    /// An integer.
+    Long(i64),
+    /// A float.
+    Double(f64),
+    /// A string, bytes that are not valid UTF-8 are replaced.
+    String(String),
+}
+
+impl Value {
+    /// Reads a scalar zval, `None` for arrays, objects and resources.
+    pub(crate) unsafe fn from_zval(zv: *mut php_sys::zval) -> Option<Value> {
+        let value = match php_sys::phprs_zval_type(zv) as u32 {
+            php_sys::IS_UNDEF | php_sys::IS_NULL => Value::Null,
+            php_sys::IS_FALSE => Value::Bool(false),
+            php_sys::IS_TRUE => Value::Bool(true),
+            php_sys::IS_LONG => Value::Long(php_sys::phprs_zval_long(zv) as i64),
+            php_sys::IS_DOUBLE => Value::Double(php_sys::phprs_zval_double(zv)),
+            php_sys::IS_STRING => {
+                let mut len = 0;
+                let s = php_sys::phprs_zval_string(zv, &mut len);
+                let bytes = slice::from_raw_parts(s as *const u8, len);
+                Value::String(String::from_utf8_lossy(bytes).into_owned())
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Writes the value to an undefined zval. Running out of memory on the
+    /// way leaves a bailout pending, it has to be handled once Rust returned.
+    pub(crate) unsafe fn to_zval(&self, zv: *mut php_sys::zval) {
+        match *self {
+            Value::Null => php_sys::phprs_zval_set_null(zv),
+            Value::Bool(b) => php_sys::phprs_zval_set_bool(zv, b as c_int),
+            Value::Long(l) => php_sys::phprs_zval_set_long(zv, l as php_sys::zend_long),
+            Value::Double(d) => php_sys::phprs_zval_set_double(zv, d),
+            Value::String(ref s) => {
+                php_sys::phprs_zval_set_string(zv, s.as_ptr() as *const c_char, s.len())
+            }
+        }
+    }
+}
+
+impl From<()> for Value {
+    fn from(_: ()) -> Value {
+        Value::Null
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(l: i64) -> Value {
+        Value::Long(l)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(d: f64) -> Value {
+        Value::Double(d)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::String(s.to_owned())
+    }
+}
+
+/// An exception a Rust function throws by returning it as its error. PHP
+/// code can catch it like any other exception.
+///
+/// ```ignore
+/// Err(Throw::new("no such user").class("App\\NotFound").code(404))
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Throw {
+    /// The class to throw, any `Throwable` class PHP knows including ones
+    /// registered with `RuntimeBuilder::exception_class`.
+    pub class: String,
+    /// The exception message.
+    pub message: String,
+    /// The exception code.
+    pub code: i64,
+}
+
+impl Throw {
+    /// An `Exception` with `message` and code 0.
+    pub fn new<S: Into<String>>(message: S) -> Throw {
+        Throw {
+            class: "Exception".to_owned(),
+            message: message.into(),
+            code: 0,
+        }
+    }
+
+    /// Sets the class to throw. An unknown class or one that is not a
+    /// `Throwable` throws `Exception` with a warning instead.
+    pub fn class(mut self, class: &str) -> Self {
+        self.class = class.to_owned();
+        self
+    }
+
+    /// Sets the exception code.
+    pub fn code(mut self, code: i64) -> Self {
+        self.code = code;
+        self
+    }
+
+    /// Asks PHP to throw the exception once the function returned. Like
+    /// `to_zval` it never bails out itself.
+    pub(crate) unsafe fn throw(&self) {
+        let class = CString::new(self.class.as_str()).unwrap_or_default();
+        php_sys::phprs_throw(
+            class.as_ptr(),
+            self.message.as_ptr() as *const c_char,
+            self.message.len(),
+            self.code as php_sys::zend_long,
+        );
+    }
+}
+
+/// Lets `?` turn any error into an `Exception` with the error's message.
+impl<E: error::Error> From<E> for Throw {
+    fn from(error: E) -> Throw {
+        Throw::new(error.to_string())
+    }
+}
+
+impl fmt::Display for Throw {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.class, self.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_throw() {
+        let throw = Throw::new("no such user").class("App\\NotFound").code(404);
+        assert_eq!(throw.to_string(), "App\\NotFound: no such user");
+        assert_eq!(throw.code, 404);
+
+        let from_io = || -> Result<(), Throw> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))?;
+            Ok(())
+        };
+        assert_eq!(from_io().unwrap_err(), Throw::new("disk full"));
+    }
+
+    #[test]
+    fn test_value_from() {
+        assert_eq!(Value::from(()), Value::Null);
+        assert_eq!(Value::from(3i64), Value::Long(3));
+        assert_eq!(Value::from("php"), Value::String("php".to_owned()));
+    }
+}
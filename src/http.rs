@@ -0,0 +1,270 @@
+//! Adapter between `http::Request`/`http::Response` and the runtime.
+//!
+//! The request method, uri, headers, cookies and body are handed to PHP the
+//! way a CGI SAPI would, the status and headers the script sets end up in the
+//! response.
+
+use std::cmp;
+use std::fmt;
+use std::ptr;
+
+use http::header::{HeaderName, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, COOKIE};
+use http::{HeaderMap, Response, StatusCode};
+
+use super::{Callbacks, Error, Request, Runtime, RuntimeBuilder};
+
+/// Context used to run a request from the `http` crate, it holds the body and
+/// collects the response.
+pub struct HttpContext {
+    body: Vec<u8>,
+    read: usize,
+    output: Vec<u8>,
+    status: u16,
+    headers: Vec<Vec<u8>>,
+}
+
+impl HttpContext {
+    /// Creates a context for a request with the given body.
+    pub fn new(body: Vec<u8>) -> Self {
+        HttpContext {
+            body,
+            read: 0,
+            output: Vec::new(),
+            status: 200,
+            headers: Vec::new(),
+        }
+    }
+
+    fn write(ctx: &mut HttpContext, buf: &[u8]) -> Result<usize, ()> {
+        ctx.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn read(ctx: &mut HttpContext, buf: *mut i8, bytes: usize) -> Result<usize, ()> {
+        let remaining = &ctx.body[ctx.read..];
+        let copied = cmp::min(bytes, remaining.len());
+        unsafe {
+            ptr::copy(remaining.as_ptr() as *const i8, buf, copied);
+        }
+        ctx.read += copied;
+        Ok(copied)
+    }
+
+    fn send_headers(ctx: &mut HttpContext, status: u16, headers: &[&[u8]]) -> Result<(), ()> {
+        ctx.status = status;
+        ctx.headers = headers.iter().map(|h| h.to_vec()).collect();
+        Ok(())
+    }
+
+    /// Adds the HttpContext to a builder, this will set all related functions.
+    pub fn add_to_builder(builder: RuntimeBuilder<HttpContext>) -> RuntimeBuilder<HttpContext> {
+        builder
+            .read(Box::new(HttpContext::read))
+            .write(Box::new(HttpContext::write))
+            .send_headers(Box::new(HttpContext::send_headers))
+    }
+
+    /// Creates the HttpContext callbacks, this can be used as a
+    /// `RuntimePool` factory.
+    pub fn callbacks() -> Callbacks<HttpContext> {
+        Callbacks::new()
+            .read(Box::new(HttpContext::read))
+            .write(Box::new(HttpContext::write))
+            .send_headers(Box::new(HttpContext::send_headers))
+    }
+
+    /// Turns the collected output into a response.
+    pub fn into_response(self) -> Response<Vec<u8>> {
+        response(self.status, &self.headers, self.output)
+    }
+}
+
+impl fmt::Debug for HttpContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HttpContext")
+            .field("body", &self.body.len())
+            .field("read", &self.read)
+            .field("output", &self.output.len())
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+/// Runs a php file for a `http::Request` and returns the response the script
+/// produced.
+pub fn execute<B>(
+    runtime: &mut Runtime<HttpContext>,
+    handle_filename: &str,
+    request: ::http::Request<B>,
+) -> Result<Response<Vec<u8>>, Error>
+where
+    B: AsRef<[u8]>,
+{
+    let info = request_info(handle_filename, &request);
+    let mut context = HttpContext::new(request.into_body().as_ref().to_vec());
+    runtime.execute_request(&info, &mut context)?;
+    Ok(context.into_response())
+}
+
+/// Builds the request information PHP sees from a `http::Request`: method,
+/// uri, query string, cookies, content type and length and every header as
+/// a `HTTP_*` server variable.
+pub fn request_info<B>(handle_filename: &str, request: &::http::Request<B>) -> Request {
+    let uri = request.uri();
+    let path_and_query = uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or_else(|| uri.path());
+    let mut info = Request::new(handle_filename)
+        .method(request.method().as_str())
+        .uri(path_and_query)
+        .query_string(uri.query().unwrap_or(""))
+        .server_var("SERVER_PROTOCOL", &format!("{:?}", request.version()));
+
+    let headers = request.headers();
+    if let Some(content_type) = header_str(headers, &CONTENT_TYPE) {
+        info = info.content_type(&content_type);
+    }
+    if let Some(length) = header_str(headers, &CONTENT_LENGTH).and_then(|l| l.parse().ok()) {
+        info = info.content_length(length);
+    }
+    let cookies: Vec<&str> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|c| c.to_str().ok())
+        .collect();
+    if !cookies.is_empty() {
+        info = info.cookies(&cookies.join("; "));
+    }
+    for name in headers.keys() {
+        if name == CONTENT_TYPE || name == CONTENT_LENGTH {
+            continue;
+        }
+        let value = if name == COOKIE {
+            Some(cookies.join("; "))
+        } else {
+            header_str(headers, name)
+        };
+        if let Some(value) = value {
+            info = info.server_var(&server_var_name(name), &value);
+        }
+    }
+    info
+}
+
+/// Builds a response from the status and raw header lines PHP sent.
+/// Lines that are not valid headers are skipped.
+pub fn response<B>(status: u16, headers: &[Vec<u8>], body: B) -> Response<B> {
+    let mut response = Response::new(body);
+    *response.status_mut() = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+    for line in headers {
+        if let Some((name, value)) = parse_header(line) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response
+}
+
+/// All values of a header joined the way CGI expects them.
+fn header_str(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.join(", "))
+    }
+}
+
+/// `Accept-Language` becomes `HTTP_ACCEPT_LANGUAGE`.
+fn server_var_name(name: &HeaderName) -> String {
+    let mut var = String::from("HTTP_");
+    for c in name.as_str().chars() {
+        var.push(if c == '-' {
+            '_'
+        } else {
+            c.to_ascii_uppercase()
+        });
+    }
+    var
+}
+
+fn parse_header(line: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+    let colon = line.iter().position(|&c| c == b':')?;
+    let (name, value) = line.split_at(colon);
+    let value = &value[1..];
+    let start = value
+        .iter()
+        .position(|&c| c != b' ' && c != b'\t')
+        .unwrap_or_else(|| value.len());
+    let name = HeaderName::from_bytes(name).ok()?;
+    let value = HeaderValue::from_bytes(&value[start..]).ok()?;
+    Some((name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_var_name() {
+        let name = HeaderName::from_static("accept-language");
+        assert_eq!(server_var_name(&name), "HTTP_ACCEPT_LANGUAGE");
+    }
+
+    #[test]
+    fn test_response() {
+        let headers = vec![
+            b"Content-type: text/plain".to_vec(),
+            b"Set-Cookie: a=1".to_vec(),
+            b"Set-Cookie:b=2".to_vec(),
+            b"not a header".to_vec(),
+        ];
+        let response = response(404, &headers, ());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        let cookies: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, vec!["a=1", "b=2"]);
+        assert_eq!(response.headers().len(), 3);
+    }
+
+    #[test]
+    fn test_request_info() {
+        let request = ::http::Request::post("http://example.com/index.php?a=1")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("Content-Length", "3")
+            .header("Cookie", "a=1")
+            .header("Cookie", "b=2")
+            .header("X-Forwarded-For", "127.0.0.1")
+            .body(())
+            .unwrap();
+        let info = request_info("/srv/index.php", &request);
+        let vars: Vec<(String, String)> = info
+            .server_vars()
+            .into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v.to_owned()))
+            .collect();
+        let var = |name: &str| {
+            vars.iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(var("REQUEST_METHOD"), Some("POST"));
+        assert_eq!(var("REQUEST_URI"), Some("/index.php?a=1"));
+        assert_eq!(var("QUERY_STRING"), Some("a=1"));
+        assert_eq!(
+            var("CONTENT_TYPE"),
+            Some("application/x-www-form-urlencoded")
+        );
+        assert_eq!(var("HTTP_X_FORWARDED_FOR"), Some("127.0.0.1"));
+        assert_eq!(var("HTTP_COOKIE"), Some("a=1; b=2"));
+        assert_eq!(var("HTTP_CONTENT_TYPE"), None);
+        assert_eq!(
+            info.cookie_data().unwrap().into_string().unwrap(),
+            "a=1; b=2"
+        );
+    }
+}
@@ -0,0 +1,69 @@
+//! Lets scripts that write nothing notice an aborted connection.
+//!
+//! PHP only asks the aborted callback when it writes or flushes. While a
+//! request with an aborted callback runs, a watcher thread interrupts its
+//! thread now and then and the interrupt asks the callback.
+
+use std::os::raw::c_void;
+use std::sync::{Mutex, MutexGuard, Once};
+use std::thread;
+use std::time::Duration;
+
+/// How often a watched request asks its aborted callback.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// The interrupt flags of the threads running a watched request.
+static WATCHED: Mutex<Vec<Flag>> = Mutex::new(Vec::new());
+static WATCHER: Once = Once::new();
+
+/// The interrupt flag of a PHP thread, see `phprs_interrupt_flag`.
+#[derive(Clone, Copy, PartialEq)]
+struct Flag(*mut c_void);
+
+// It is only handed to `phprs_interrupt`, which works from any thread.
+unsafe impl Send for Flag {}
+
+/// Watches the request running on the current thread until the returned
+/// guard is dropped.
+pub(crate) fn watch() -> Watch {
+    WATCHER.call_once(|| {
+        thread::Builder::new()
+            .name("php-abort-watcher".to_owned())
+            .spawn(run)
+            .expect("failed to start the abort watcher");
+    });
+    let flag = Flag(unsafe { php_sys::phprs_interrupt_flag() });
+    watched().push(flag);
+    Watch { flag }
+}
+
+/// Stops watching a request when dropped.
+pub(crate) struct Watch {
+    flag: Flag,
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        // Once removed the flag is not touched any more, the thread owning
+        // it may go away.
+        let mut watched = watched();
+        if let Some(index) = watched.iter().position(|flag| *flag == self.flag) {
+            watched.swap_remove(index);
+        }
+    }
+}
+
+fn watched() -> MutexGuard<'static, Vec<Flag>> {
+    WATCHED
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn run() {
+    loop {
+        thread::sleep(INTERVAL);
+        for flag in watched().iter() {
+            unsafe { php_sys::phprs_interrupt(flag.0) };
+        }
+    }
+}
@@ -1,8 +1,47 @@
 //! A rust wrapper around libphp
 
 extern crate php_sys;
-use std::ffi::CString;
+
+#[cfg(feature = "async")]
+mod async_runtime;
+mod diagnostic;
+mod error;
+mod exception;
+#[cfg(feature = "fastcgi")]
+mod fastcgi;
+mod function;
+#[cfg(feature = "http")]
+pub mod http;
+mod interrupt;
+mod opcache;
+mod pool;
+mod request;
+#[cfg(feature = "service")]
+mod service;
+mod session;
+
+#[cfg(feature = "async")]
+pub use async_runtime::{AsyncContext, AsyncRequest, AsyncRuntime, Head, Output};
+pub use diagnostic::{Diagnostic, Level};
+pub use error::{Error, Execution, Termination};
+pub use exception::{Exception, ExceptionChain, Frame};
+#[cfg(feature = "fastcgi")]
+pub use fastcgi::{FastCgiContext, FastCgiServer};
+pub use function::{Throw, Value};
+pub use opcache::OpcacheStatus;
+pub use pool::RuntimePool;
+pub use request::Request;
+#[cfg(feature = "service")]
+pub use service::{Body, PhpService};
+pub use session::{EvalError, Session};
+
+use request::RequestInfo;
+
+use std::any::Any;
+use std::ffi::{CStr, CString};
+use std::fmt;
 use std::os::raw::{c_char, c_int, c_uchar, c_void};
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 use std::slice;
 
@@ -19,13 +58,22 @@ impl<T> Runtime<T> {
     ///
     /// `name` - is the short name of the runtime
     /// `long_name` - is the long/descriptive name of the runtime
-    /// `threads` - number of runtime threads
+    /// `threads` - number of runtime threads, a PHP built without ZTS can
+    /// only run on a single thread
     pub fn new(name: &str, long_name: &str, threads: usize) -> RuntimeBuilder<T> {
         let threads = if threads > 0 { threads } else { 1 };
+        #[cfg(not(php_zts))]
+        assert_eq!(
+            threads, 1,
+            "PHP without ZTS can only run on a single thread"
+        );
         unsafe {
-            php_sys::tsrm_startup(threads as i32, 1, 0, ptr::null_mut());
-            php_sys::ts_resource_ex(0, ptr::null_mut());
-            php_sys::zend_tsrmls_cache_update();
+            #[cfg(php_zts)]
+            {
+                php_sys::tsrm_startup(threads as i32, 1, 0, ptr::null_mut());
+                php_sys::ts_resource_ex(0, ptr::null_mut());
+                php_sys::zend_tsrmls_cache_update();
+            }
 
             php_sys::zend_signal_startup();
 
@@ -39,79 +87,142 @@ impl<T> Runtime<T> {
             module.startup = Some(sapi_server_startup::<T>);
             module.shutdown = Some(sapi_server_shutdown::<T>);
             module.ub_write = Some(sapi_server_ub_write::<T>);
-            module.flush = Some(sapi_server_flush);
+            module.flush = Some(sapi_server_flush::<T>);
             module.sapi_error = Some(php_sys::zend_error);
             module.send_headers = Some(sapi_server_send_headers::<T>);
             module.read_post = Some(sapi_server_read_post::<T>);
             module.read_cookies = Some(sapi_server_read_cookies::<T>);
             module.register_server_variables = Some(sapi_server_register_variables::<T>);
             module.log_message = Some(sapi_server_log_message::<T>);
+            // PHP may bail out of any of them, which must not skip Rust frames
+            php_sys::phprs_guard_sapi_module(&mut *module);
             RuntimeBuilder {
-                callbacks: Callbacks::<T> {
-                    startup: None,
-                    shutdown: None,
-                    write: None,
-                    read: None,
-                },
+                callbacks: Callbacks::new(),
                 module,
+                threads,
+                ini: String::new(),
+                opcache: false,
             }
         }
     }
 
     /// Executes php code, given a php file and a context. The context can be used
     /// to pass additional information to the callbacks.
-    pub fn execute(&mut self, handle_filename: &str, context: &mut T) -> Result<(), ()>
+    pub fn execute(&mut self, handle_filename: &str, context: &mut T) -> Result<Execution, Error>
+    where
+        T: std::fmt::Debug,
+    {
+        self.execute_request(&Request::new(handle_filename).method("POST"), context)
+    }
+
+    /// Executes php code for a request. The request describes the method, uri,
+    /// cookies and `$_SERVER` variables PHP sees, the context can be used to
+    /// pass additional information to the callbacks.
+    ///
+    /// The result tells a script that ran to its end apart from one that
+    /// called `exit()`, a fatal error is returned as `Error::Fatal`.
+    pub fn execute_request(
+        &mut self,
+        request: &Request,
+        context: &mut T,
+    ) -> Result<Execution, Error>
     where
         T: std::fmt::Debug,
     {
-        let mode = CString::new("rb").unwrap();
         unsafe {
+            #[cfg(php_zts)]
             php_sys::ts_resource_ex(0, ptr::null_mut());
-            let handle_filename = CString::new(handle_filename).unwrap();
-            let fp = php_sys::phprpm_fopen(handle_filename.as_ptr() as *const i8, mode.as_ptr());
-            let mut handle = php_sys::_zend_file_handle__bindgen_ty_1::default();
-            handle.fp = fp;
-            let script = Box::new(php_sys::zend_file_handle {
-                handle: handle,
-                filename: handle_filename.as_ptr() as *const i8,
-                opened_path: ptr::null_mut(),
-                type_: php_sys::zend_stream_type_ZEND_HANDLE_FP,
-                free_filename: 0,
-            });
-            let script_ptr = Box::into_raw(script);
-
-            (*php_sys::sg_sapi_headers()).http_response_code = 200;
-            let ctx = Box::new(PHPContext {
-                callbacks: &mut self.callbacks,
-                context: context,
-            });
-            let context_ptr = Box::into_raw(ctx);
-            php_sys::sg_set_server_context(context_ptr as *mut c_void);
-            php_sys::php_request_startup();
-
-            php_sys::php_execute_script(script_ptr);
-
-            drop(Box::from_raw(script_ptr));
-
-            // Since we pass in the context as a mutable ref we don't need this any more
-            //let context = php_sys::sg_server_context() as *mut PHPContext<T>;
-
-            //let result = (*context).context;
-
-            // TODO move this into the request shutdown block
-            // note: strangely enough, php_request_shutdown will not call our request shutdown callback
-            if !(*php_sys::sg_request_info()).cookie_data.is_null() {
-                drop(CString::from_raw((*php_sys::sg_request_info()).cookie_data));
+            let handle_filename = request::c_string(request.script());
+            let has_aborted = self.callbacks.aborted.is_some();
+
+            let (context_ptr, info, started) = begin_request(&mut self.callbacks, request, context);
+            if !started {
+                return Err(end_request(context_ptr, info).into_startup_error());
             }
-            (*php_sys::sg_request_info()).cookie_data = ptr::null_mut();
-            drop(Box::from_raw(
-                php_sys::sg_server_context() as *mut PHPContext<T>
-            ));
-            php_sys::sg_set_server_context(ptr::null_mut());
+            php_sys::phprs_clear_last_error();
 
-            php_sys::php_request_shutdown(ptr::null_mut());
+            // a script writing nothing only asks the aborted callback when
+            // it is interrupted
+            let watch = if has_aborted {
+                Some(interrupt::watch())
+            } else {
+                None
+            };
+            // Fails if the script bailed out, because of exit() or a fatal
+            // error.
+            let completed = php_sys::phprs_execute_file(handle_filename.as_ptr()) != 0;
+            drop(watch);
+            let fatal = if completed {
+                None
+            } else {
+                error::last_fatal_error()
+            };
+            // an aborted connection bails out like exit() does
+            let connection = php_sys::pg_connection_status();
+            let aborted =
+                !completed && (connection & php_sys::PHP_CONNECTION_ABORTED as c_int) != 0;
 
-            Ok(())
+            let ended = end_request(context_ptr, info);
+            if let Some(payload) = ended.panic {
+                return Err(Error::panic(payload).with_diagnostics(ended.diagnostics));
+            }
+            if let Some(exception) = ended.exception {
+                return Err(Error::UncaughtException {
+                    exception,
+                    diagnostics: ended.diagnostics,
+                });
+            }
+            let termination = match fatal {
+                Some(error) => return Err(error.with_diagnostics(ended.diagnostics)),
+                None if aborted => Termination::Aborted,
+                // a shutdown function can exit() as well
+                None if completed && ended.exit_status == 0 => Termination::Completed,
+                None => Termination::Exit(ended.exit_status),
+            };
+            Ok(Execution {
+                termination,
+                diagnostics: ended.diagnostics,
+            })
+        }
+    }
+
+    /// Starts a request that stays alive until the returned session is
+    /// dropped, code evaluated in it shares variables, functions and classes.
+    /// The script of the request is not executed, it only names the request.
+    pub fn session<'a>(
+        &'a mut self,
+        request: &'a Request,
+        context: &'a mut T,
+    ) -> Result<Session<'a, T>, Error> {
+        unsafe {
+            let (context_ptr, info, started) = begin_request(&mut self.callbacks, request, context);
+            if !started {
+                return Err(end_request(context_ptr, info).into_startup_error());
+            }
+            Ok(Session::new(context_ptr, info))
+        }
+    }
+
+    /// The statistics of OPcache, `None` if it is not loaded or not running.
+    /// They are read in a request of their own, which gets `context`.
+    pub fn opcache_status(&mut self, context: &mut T) -> Result<Option<OpcacheStatus>, Error> {
+        let request = Request::new("opcache-status");
+        unsafe {
+            let (context_ptr, info, started) =
+                begin_request(&mut self.callbacks, &request, context);
+            let status = if started {
+                OpcacheStatus::current()
+            } else {
+                None
+            };
+            let ended = end_request(context_ptr, info);
+            if let Some(payload) = ended.panic {
+                return Err(Error::panic(payload).with_diagnostics(ended.diagnostics));
+            }
+            if !started {
+                return Err(Error::Bailout);
+            }
+            Ok(status)
         }
     }
 }
@@ -119,17 +230,183 @@ impl<T> Runtime<T> {
 struct PHPContext<'ctx, T: 'ctx> {
     callbacks: &'ctx mut Callbacks<T>,
     context: &'ctx mut T,
+    request: &'ctx Request,
+    /// The payload of the first callback that panicked.
+    panic: Option<Box<dyn Any + Send>>,
+    diagnostics: Vec<Diagnostic>,
+    /// The exception nothing caught, if the script threw one.
+    exception: Option<Exception>,
+}
+
+/// What is left of a request once it is shut down.
+struct Ended {
+    exit_status: i32,
+    panic: Option<Box<dyn Any + Send>>,
+    diagnostics: Vec<Diagnostic>,
+    exception: Option<Exception>,
+}
+
+impl Ended {
+    /// The error of a request that failed to start.
+    fn into_startup_error(self) -> Error {
+        match self.panic {
+            Some(payload) => Error::panic(payload).with_diagnostics(self.diagnostics),
+            None => Error::Bailout,
+        }
+    }
+}
+
+/// Starts a request on the current thread. The returned context is owned by
+/// `SG(server_context)` until it is handed back to `end_request`, which has to
+/// be called even if starting the request failed.
+unsafe fn begin_request<'a, T>(
+    callbacks: &'a mut Callbacks<T>,
+    request: &'a Request,
+    context: &'a mut T,
+) -> (*mut PHPContext<'a, T>, RequestInfo, bool) {
+    #[cfg(php_zts)]
+    php_sys::ts_resource_ex(0, ptr::null_mut());
+    (*php_sys::sg_sapi_headers()).http_response_code = 200;
+    let info = RequestInfo::install(request);
+    let context_ptr = Box::into_raw(Box::new(PHPContext {
+        callbacks,
+        context,
+        request,
+        panic: None,
+        diagnostics: Vec::new(),
+        exception: None,
+    }));
+    php_sys::sg_set_server_context(context_ptr as *mut c_void);
+    let started = php_sys::phprs_request_startup() == php_sys::ZEND_RESULT_CODE_SUCCESS as c_int;
+    if started && request.is_cli() {
+        php_sys::phprs_register_file_handles();
+    }
+    (context_ptr, info, started)
+}
+
+/// Shuts down the request started by `begin_request`.
+unsafe fn end_request<T>(context_ptr: *mut PHPContext<T>, info: RequestInfo) -> Ended {
+    // The context has to outlive the shutdown, it flushes the output
+    // and sends the headers if that didn't happen yet.
+    php_sys::phprs_request_shutdown();
+    let exit_status = php_sys::eg_exit_status() as i32;
+
+    if !(*php_sys::sg_request_info()).cookie_data.is_null() {
+        drop(CString::from_raw((*php_sys::sg_request_info()).cookie_data));
+    }
+    (*php_sys::sg_request_info()).cookie_data = ptr::null_mut();
+    let context = Box::from_raw(context_ptr);
+    php_sys::sg_set_server_context(ptr::null_mut());
+    info.uninstall();
+    Ended {
+        exit_status,
+        panic: context.panic,
+        diagnostics: context.diagnostics,
+        exception: context.exception,
+    }
 }
 
 pub type StartupCallback<T> = FnMut(&mut T) -> Result<(), ()>;
 pub type ShutdownCallback<T> = FnMut(&mut T) -> Result<(), ()>;
 pub type WriteCallback<T> = FnMut(&mut T, &[u8]) -> Result<usize, ()>;
 pub type ReadCallback<T> = FnMut(&mut T, *mut i8, usize) -> Result<usize, ()>;
-struct Callbacks<T> {
+pub type AbortedCallback<T> = dyn FnMut(&mut T) -> bool;
+pub type SendHeadersCallback<T> = dyn FnMut(&mut T, u16, &[&[u8]]) -> Result<(), ()>;
+pub type DiagnosticCallback<T> = dyn FnMut(&mut T, &Diagnostic);
+pub type FunctionCallback<T> = dyn Fn(&mut T, &[Value]) -> Result<Value, Throw> + Send + Sync;
+
+/// The set of callbacks a runtime uses to talk to its context.
+///
+/// `RuntimeBuilder` sets them for a single runtime, a `RuntimePool` creates a
+/// fresh set for every worker thread so handler state is never shared.
+///
+/// A callback that panics stops the request it runs for, the panic is
+/// returned as `Error::Panic` and the runtime stays usable.
+pub struct Callbacks<T> {
     startup: Option<Box<StartupCallback<T>>>,
     shutdown: Option<Box<ShutdownCallback<T>>>,
     write: Option<Box<WriteCallback<T>>>,
     read: Option<Box<ReadCallback<T>>>,
+    aborted: Option<Box<AbortedCallback<T>>>,
+    send_headers: Option<Box<SendHeadersCallback<T>>>,
+    diagnostic: Option<Box<DiagnosticCallback<T>>>,
+}
+
+impl<T> Callbacks<T> {
+    /// Creates an empty set of callbacks.
+    pub fn new() -> Self {
+        Callbacks {
+            startup: None,
+            shutdown: None,
+            write: None,
+            read: None,
+            aborted: None,
+            send_headers: None,
+            diagnostic: None,
+        }
+    }
+
+    /// See `RuntimeBuilder::startup`.
+    pub fn startup(mut self, callback: Box<StartupCallback<T>>) -> Self {
+        self.startup = Some(callback);
+        self
+    }
+
+    /// See `RuntimeBuilder::shutdown`.
+    pub fn shutdown(mut self, callback: Box<ShutdownCallback<T>>) -> Self {
+        self.shutdown = Some(callback);
+        self
+    }
+
+    /// See `RuntimeBuilder::write`.
+    pub fn write(mut self, callback: Box<WriteCallback<T>>) -> Self {
+        self.write = Some(callback);
+        self
+    }
+
+    /// See `RuntimeBuilder::read`.
+    pub fn read(mut self, callback: Box<ReadCallback<T>>) -> Self {
+        self.read = Some(callback);
+        self
+    }
+
+    /// See `RuntimeBuilder::aborted`.
+    pub fn aborted(mut self, callback: Box<AbortedCallback<T>>) -> Self {
+        self.aborted = Some(callback);
+        self
+    }
+
+    /// See `RuntimeBuilder::send_headers`.
+    pub fn send_headers(mut self, callback: Box<SendHeadersCallback<T>>) -> Self {
+        self.send_headers = Some(callback);
+        self
+    }
+
+    /// See `RuntimeBuilder::diagnostic`.
+    pub fn diagnostic(mut self, callback: Box<DiagnosticCallback<T>>) -> Self {
+        self.diagnostic = Some(callback);
+        self
+    }
+}
+
+impl<T> Default for Callbacks<T> {
+    fn default() -> Self {
+        Callbacks::new()
+    }
+}
+
+impl<T> fmt::Debug for Callbacks<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("startup", &self.startup.is_some())
+            .field("shutdown", &self.shutdown.is_some())
+            .field("write", &self.write.is_some())
+            .field("read", &self.read.is_some())
+            .field("aborted", &self.aborted.is_some())
+            .field("send_headers", &self.send_headers.is_some())
+            .field("diagnostic", &self.diagnostic.is_some())
+            .finish()
+    }
 }
 
 /// A simple IOContext that handles reading from a buffer and writing to a buffer.
@@ -169,14 +446,30 @@ impl IOContext {
             .read(Box::new(IOContext::read))
             .write(Box::new(IOContext::write))
     }
+
+    /// Creates the IOContext callbacks, this can be used as a `RuntimePool`
+    /// factory.
+    pub fn callbacks() -> Callbacks<IOContext> {
+        Callbacks::new()
+            .read(Box::new(IOContext::read))
+            .write(Box::new(IOContext::write))
+    }
 }
 
 /// Runtime builder to set callbacks as required.
 pub struct RuntimeBuilder<T> {
     callbacks: Callbacks<T>,
     module: Box<php_sys::_sapi_module_struct>,
+    threads: usize,
+    ini: String,
+    opcache: bool,
 }
 
+/// The SAPI name the runtime starts under with OPcache, which only caches for
+/// the SAPIs it knows and for the CLI with `opcache.enable_cli`. PHP looks
+/// for `php-cli.ini` under this name as well.
+const OPCACHE_SAPI_NAME: &[u8] = b"cli\0";
+
 impl<T> RuntimeBuilder<T> {
     /// The startup callback is called when the php runtime is started. It
     /// can be used to initiate an environment as needed.
@@ -207,110 +500,424 @@ impl<T> RuntimeBuilder<T> {
         self
     }
 
+    /// This is called before output is written or flushed to check if the
+    /// client went away, and every 100ms while the script runs so one that
+    /// writes nothing notices as well. Returning `true` marks the connection
+    /// as aborted, `connection_aborted()` will return true in PHP and the
+    /// script is stopped unless `ignore_user_abort` is set.
+    ///
+    /// A failing write callback is treated the same way.
+    pub fn aborted(mut self, callback: Box<AbortedCallback<T>>) -> Self {
+        self.callbacks.aborted = Some(callback);
+        self
+    }
+
+    /// This is called once per request right before the first output is
+    /// written, or at the end of the request if there was none. It gets the
+    /// response status and the raw header lines (`Name: value`) the script
+    /// set, including the default `Content-type`.
+    pub fn send_headers(mut self, callback: Box<SendHeadersCallback<T>>) -> Self {
+        self.callbacks.send_headers = Some(callback);
+        self
+    }
+
+    /// This is called for every warning, notice, deprecation or error PHP
+    /// reports during a request, before it is displayed or logged. Errors a
+    /// user error handler dealt with are not reported. The diagnostics are
+    /// also part of the `Execution` a request returns, or of the `Error` if a
+    /// fatal error, an uncaught exception or a panic stopped it.
+    pub fn diagnostic(mut self, callback: Box<DiagnosticCallback<T>>) -> Self {
+        self.callbacks.diagnostic = Some(callback);
+        self
+    }
+
+    /// Registers a PHP function implemented in Rust. It gets the context of
+    /// the request and the arguments, which have to be scalars. Returning an
+    /// error throws it as a PHP exception, PHP code can catch it as usual.
+    ///
+    /// The function is shared by all workers of a pool.
+    pub fn function(self, name: &str, callback: Box<FunctionCallback<T>>) -> Self {
+        let name = CString::new(name).unwrap();
+        // lives as long as the module
+        let data = Box::into_raw(Box::new(callback));
+        unsafe {
+            php_sys::phprs_add_function(name.as_ptr(), data as *mut c_void);
+        }
+        self
+    }
+
+    /// Defines an exception class that extends `parent`, e.g. `Exception`,
+    /// `RuntimeException` or another class defined this way. Rust functions
+    /// can throw it and PHP code can catch it.
+    pub fn exception_class(self, name: &str, parent: &str) -> Self {
+        let name = CString::new(name).unwrap();
+        let parent = CString::new(parent).unwrap();
+        unsafe {
+            php_sys::phprs_add_exception_class(name.as_ptr(), parent.as_ptr());
+        }
+        self
+    }
+
+    /// Sets an ini directive, it takes precedence over php.ini.
+    pub fn ini(mut self, name: &str, value: &str) -> Self {
+        self.ini.push_str(name);
+        self.ini.push('=');
+        self.ini.push_str(value);
+        self.ini.push('\n');
+        self
+    }
+
+    /// Loads OPcache and turns it on, so scripts are compiled once and not
+    /// on every request. It is loaded from the PHP php-sys was built against,
+    /// which needs the `opcache` feature when PHP is built from source.
+    ///
+    /// Further `opcache.*` directives can be set with `ini`. The runtime
+    /// starts up as `cli` then, so PHP reads `php-cli.ini` instead of the
+    /// php.ini named after the runtime.
+    ///
+    /// # Panics
+    ///
+    /// If php-sys found no OPcache, use `opcache_extension` with the path of
+    /// an `opcache.so` then.
+    pub fn opcache(self) -> Self {
+        match php_sys::OPCACHE {
+            Some(path) => self.opcache_extension(path),
+            None => panic!("php-sys was built without OPcache, enable the opcache feature"),
+        }
+    }
+
+    /// Like `opcache`, but loads OPcache from `path`.
+    pub fn opcache_extension(mut self, path: &str) -> Self {
+        self.opcache = true;
+        self.ini("zend_extension", path)
+            .ini("opcache.enable", "1")
+            .ini("opcache.enable_cli", "1")
+    }
+
     /// Finalizes the builder, creates and starts the runtime.
-    pub fn start(self) -> Runtime<T> {
+    pub fn start(mut self) -> Runtime<T> {
         unsafe {
+            if !self.ini.is_empty() {
+                // lives as long as the module
+                self.module.ini_entries = request::c_string(&self.ini).into_raw();
+            }
+            let name = self.module.name;
+            if self.opcache {
+                // OPcache checks the name once, while it starts up
+                self.module.name = OPCACHE_SAPI_NAME.as_ptr() as *mut c_char;
+            }
             let module_ptr = Box::into_raw(self.module);
             php_sys::sapi_startup(module_ptr);
-
-            let request_method = CString::new("POST").unwrap();
-            let path_translated = CString::new("/some/file").unwrap();
-            let content_type = CString::new("text/html").unwrap();
-            (*php_sys::sg_request_info()).request_method = request_method.as_ptr();
-            (*php_sys::sg_request_info()).content_length = 0;
-            (*php_sys::sg_request_info()).path_translated = path_translated.into_raw();
-            (*php_sys::sg_request_info()).content_type = content_type.as_ptr();
-
-            php_sys::php_module_startup(module_ptr, ptr::null_mut(), 0);
+            if php_sys::phprs_module_startup(module_ptr)
+                != php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
+            {
+                panic!("failed to start the php module");
+            }
+            (*module_ptr).name = name;
+            php_sys::phprs_set_sapi_name(name);
+            php_sys::phprs_hook_error_cb(Some(sapi_server_error::<T>));
+            php_sys::phprs_hook_uncaught_exception(Some(sapi_server_exception::<T>));
+            php_sys::phprs_hook_functions(Some(sapi_server_function::<T>));
+            php_sys::phprs_hook_interrupt(Some(sapi_server_interrupt::<T>));
         }
         Runtime {
             callbacks: self.callbacks,
         }
     }
+
+    /// Finalizes the builder and starts a pool of worker threads, one per
+    /// thread passed to `Runtime::new`.
+    ///
+    /// Each worker gets its own TSRM resources and its own set of callbacks
+    /// created by `factory`, which is called with the index of the worker.
+    /// The callbacks set on the builder itself are not used by the pool.
+    pub fn start_pool<F>(self, factory: F) -> RuntimePool<T>
+    where
+        F: Fn(usize) -> Callbacks<T> + Send + Sync + 'static,
+        T: fmt::Debug + Send + 'static,
+    {
+        let threads = self.threads;
+        let _runtime = self.start();
+        RuntimePool::new(threads, factory)
+    }
+}
+
+/// Runs a callback with the context of the current request.
+///
+/// Unwinding into PHP's C frames is undefined behaviour, so a panic is caught
+/// here and kept in the context until the request is done. PHP is asked to
+/// bail out once the callback returned to its C trampoline, which stops the
+/// script.
+unsafe fn guard<T, R, F>(context: *mut PHPContext<T>, default: R, f: F) -> R
+where
+    F: FnOnce(&mut PHPContext<T>) -> R,
+{
+    if context.is_null() {
+        return default;
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *context))) {
+        Ok(result) => result,
+        Err(payload) => {
+            // the first panic is the interesting one
+            if (*context).panic.is_none() {
+                (*context).panic = Some(payload);
+            }
+            php_sys::phprs_bailout();
+            default
+        }
+    }
 }
 
+// All of these are called through the trampolines `phprs_guard_sapi_module`
+// installs, they must neither unwind nor bail out.
+
 unsafe extern "C" fn sapi_server_startup<T>(_module: *mut php_sys::sapi_module_struct) -> c_int {
     let context = php_sys::sg_server_context() as *mut PHPContext<T>;
-    if let Some(ref mut cb) = (*context).callbacks.startup {
-        if cb(&mut (*context).context).is_ok() {
-            php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
+    let failure = php_sys::ZEND_RESULT_CODE_FAILURE as c_int;
+    guard(context, failure, |context| {
+        if let Some(ref mut cb) = context.callbacks.startup {
+            if cb(&mut context.context).is_ok() {
+                php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
+            } else {
+                failure
+            }
         } else {
-            php_sys::ZEND_RESULT_CODE_FAILURE as c_int
+            php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
         }
-    } else {
-        php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
-    }
+    })
 }
 
 unsafe extern "C" fn sapi_server_shutdown<T>(_module: *mut php_sys::sapi_module_struct) -> c_int {
     let context = php_sys::sg_server_context() as *mut PHPContext<T>;
-    if let Some(ref mut cb) = (*context).callbacks.shutdown {
-        if cb(&mut (*context).context).is_ok() {
-            php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
+    let failure = php_sys::ZEND_RESULT_CODE_FAILURE as c_int;
+    guard(context, failure, |context| {
+        if let Some(ref mut cb) = context.callbacks.shutdown {
+            if cb(&mut context.context).is_ok() {
+                php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
+            } else {
+                failure
+            }
         } else {
-            php_sys::ZEND_RESULT_CODE_FAILURE as c_int
+            php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
         }
-    } else {
-        php_sys::ZEND_RESULT_CODE_SUCCESS as c_int
-    }
+    })
 }
 
 unsafe extern "C" fn sapi_server_ub_write<T>(s: *const c_char, s_len: usize) -> usize {
     let context = php_sys::sg_server_context() as *mut PHPContext<T>;
-    if let Some(ref mut cb) = (*context).callbacks.write {
-        let s_: *const c_uchar = s as *const c_uchar;
-        let rs = slice::from_raw_parts(s_, s_len);
-        if let Ok(size) = cb(&mut (*context).context, rs) {
-            size
+    guard(context, 0, |context| {
+        if client_aborted(context) {
+            php_sys::phprs_abort_connection();
+            return 0;
+        }
+        let written = if let Some(ref mut cb) = context.callbacks.write {
+            let s_: *const c_uchar = s as *const c_uchar;
+            let rs = slice::from_raw_parts(s_, s_len);
+            cb(&mut context.context, rs)
         } else {
-            0
+            Ok(0)
+        };
+        match written {
+            Ok(size) => size,
+            Err(()) => {
+                // The client can't be written to any more, treat it as gone.
+                php_sys::phprs_abort_connection();
+                0
+            }
         }
-    } else {
-        0
-    }
+    })
 }
 
-unsafe extern "C" fn sapi_server_flush(_server_context: *mut c_void) {
-    if php_sys::sg_headers_sent() == 1 {
-        return;
+// Sending the headers is left to the trampoline.
+unsafe extern "C" fn sapi_server_flush<T>(server_context: *mut c_void) {
+    let context = server_context as *mut PHPContext<T>;
+    guard(context, (), |context| {
+        if client_aborted(context) {
+            php_sys::phprs_abort_connection();
+        }
+    })
+}
+
+// Called between two opcodes while `interrupt` watches the request.
+unsafe extern "C" fn sapi_server_interrupt<T>() {
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    guard(context, (), |context| {
+        if client_aborted(context) {
+            php_sys::phprs_abort_connection();
+        }
+    })
+}
+
+/// Asks the embedder if the client went away.
+fn client_aborted<T>(context: &mut PHPContext<T>) -> bool {
+    if let Some(ref mut cb) = context.callbacks.aborted {
+        cb(&mut context.context)
+    } else {
+        false
     }
-    php_sys::sapi_send_headers();
 }
 
 unsafe extern "C" fn sapi_server_send_headers<T>(
-    _sapi_headers: *mut php_sys::sapi_headers_struct,
+    sapi_headers: *mut php_sys::sapi_headers_struct,
 ) -> c_int {
-    // TODO
     // used so flush can try and send headers prior to output
     php_sys::sg_set_headers_sent(1);
 
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    if context.is_null() {
+        return php_sys::SAPI_HEADER_SENT_SUCCESSFULLY as c_int;
+    }
+    let sent = guard(context, Err(()), |context| {
+        if let Some(ref mut cb) = context.callbacks.send_headers {
+            let mut headers: Vec<&[u8]> = Vec::new();
+            php_sys::phprs_sapi_headers_each(
+                sapi_headers,
+                Some(collect_header),
+                &mut headers as *mut Vec<&[u8]> as *mut c_void,
+            );
+            let status = (*sapi_headers).http_response_code as u16;
+            cb(&mut context.context, status, &headers)
+        } else {
+            Ok(())
+        }
+    });
     // bindgen treats this as a `c_uint` type but this function requires a c_int
-    php_sys::SAPI_HEADER_SENT_SUCCESSFULLY as c_int
+    match sent {
+        Ok(()) => php_sys::SAPI_HEADER_SENT_SUCCESSFULLY as c_int,
+        Err(()) => php_sys::SAPI_HEADER_SEND_FAILED as c_int,
+    }
+}
+
+unsafe extern "C" fn collect_header(headers: *mut c_void, header: *const c_char, len: usize) {
+    let headers = &mut *(headers as *mut Vec<&[u8]>);
+    headers.push(slice::from_raw_parts(header as *const c_uchar, len));
 }
 
 unsafe extern "C" fn sapi_server_read_post<T>(buf: *mut c_char, bytes: usize) -> usize {
     let context = php_sys::sg_server_context() as *mut PHPContext<T>;
-    if let Some(ref mut cb) = (*context).callbacks.read {
-        if let Ok(copied) = cb(&mut (*context).context, buf as *mut i8, bytes) {
-            copied
+    guard(context, 0, |context| {
+        if let Some(ref mut cb) = context.callbacks.read {
+            if let Ok(copied) = cb(&mut context.context, buf as *mut i8, bytes) {
+                copied
+            } else {
+                0
+            }
         } else {
             0
         }
-    } else {
-        0
-    }
+    })
 }
 
 unsafe extern "C" fn sapi_server_read_cookies<T>() -> *mut c_char {
-    //TODO
-    ptr::null_mut()
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    guard(context, ptr::null_mut(), |context| {
+        // freed again once the request is done
+        match context.request.cookie_data() {
+            Some(cookies) => cookies.into_raw(),
+            None => ptr::null_mut(),
+        }
+    })
 }
 
-unsafe extern "C" fn sapi_server_register_variables<T>(_track_vars_array: *mut php_sys::zval) {
-    //TODO
+unsafe extern "C" fn sapi_server_register_variables<T>(track_vars_array: *mut php_sys::zval) {
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    guard(context, (), |context| {
+        // A bailout in here is held back until this returned to the
+        // trampoline.
+        for (name, value) in context.request.server_vars() {
+            php_sys::phprs_register_variable(
+                name.as_ptr() as *mut c_char,
+                value.as_ptr() as *mut c_char,
+                value.len(),
+                track_vars_array,
+            );
+        }
+    })
+}
+
+// Called from the zend_error_cb hook, see `phprs_hook_error_cb`.
+unsafe extern "C" fn sapi_server_error<T>(
+    error_type: c_int,
+    error_filename: *const c_char,
+    error_lineno: u32,
+    message: *const c_char,
+    message_len: usize,
+) {
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    guard(context, (), |context| {
+        let level = match Level::from_type(error_type as i32) {
+            Some(level) => level,
+            None => return,
+        };
+        let file = if error_filename.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(error_filename)
+                .to_string_lossy()
+                .into_owned()
+        };
+        let message = slice::from_raw_parts(message as *const c_uchar, message_len);
+        let diagnostic = Diagnostic {
+            level,
+            message: String::from_utf8_lossy(message).into_owned(),
+            file,
+            line: error_lineno,
+        };
+        if let Some(ref mut cb) = context.callbacks.diagnostic {
+            cb(&mut context.context, &diagnostic);
+        }
+        context.diagnostics.push(diagnostic);
+    })
+}
+
+// Called with an exception nothing caught, right before PHP reports it as a
+// fatal error, see `phprs_hook_uncaught_exception`.
+unsafe extern "C" fn sapi_server_exception<T>(exception: *mut php_sys::zval) {
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    guard(context, (), |context| {
+        context.exception = Some(Exception::describe(exception));
+    })
+}
+
+// The handler of every function registered with `RuntimeBuilder::function`,
+// `data` is the callback.
+unsafe extern "C" fn sapi_server_function<T>(
+    data: *mut c_void,
+    name: *const c_char,
+    args: *mut php_sys::zval,
+    argc: u32,
+    return_value: *mut php_sys::zval,
+) {
+    let context = php_sys::sg_server_context() as *mut PHPContext<T>;
+    let callback = &*(data as *const Box<FunctionCallback<T>>);
+    guard(context, (), |context| {
+        let mut values = Vec::with_capacity(argc as usize);
+        for i in 0..argc as usize {
+            match Value::from_zval(args.add(i)) {
+                Some(value) => values.push(value),
+                None => {
+                    let name = CStr::from_ptr(name).to_string_lossy();
+                    let message = format!("{}() expects scalar arguments, #{} is not", name, i + 1);
+                    Throw::new(message).class("TypeError").throw();
+                    return;
+                }
+            }
+        }
+        // neither bails out, the trampoline does once this returned
+        match callback(&mut context.context, &values) {
+            Ok(value) => value.to_zval(return_value),
+            Err(throw) => throw.throw(),
+        }
+    })
 }
 
-unsafe extern "C" fn sapi_server_log_message<T>(_ebmessage: *mut c_char, _syslog_type_int: c_int) {
+/// The message of the log_message callback is const since PHP 8.
+#[cfg(php80)]
+type LogMessage = *const c_char;
+#[cfg(not(php80))]
+type LogMessage = *mut c_char;
+
+unsafe extern "C" fn sapi_server_log_message<T>(_ebmessage: LogMessage, _syslog_type_int: c_int) {
     //TODO
 }
 
@@ -333,7 +940,17 @@ mod tests {
     #[test]
     fn test_execution() {
         let mut runtime =
-            IOContext::add_to_builder(Runtime::new("php-test", "PHP Test Runtime", 1)).start();
+            IOContext::add_to_builder(Runtime::new("php-test", "PHP Test Runtime", 1))
+                .function(
+                    "test_reserve",
+                    Box::new(|_ctx: &mut IOContext, args: &[Value]| match args.first() {
+                        Some(&Value::Long(n)) if n <= 10 => Ok(Value::Long(10 - n)),
+                        _ => Err(Throw::new("quota exceeded")
+                            .class("RuntimeException")
+                            .code(429)),
+                    }),
+                )
+                .start();
         assert_eq!(
             run(&mut runtime, "hello".into()),
             "php got: hello".to_string()
@@ -342,5 +959,98 @@ mod tests {
             run(&mut runtime, "world".into()),
             "php got: world".to_string()
         );
+        check_session(&mut runtime);
+        check_termination(&mut runtime);
+        check_function(&mut runtime);
+        check_aborted(&mut runtime);
+    }
+
+    fn check_aborted(runtime: &mut Runtime<IOContext>) {
+        runtime.callbacks.aborted = Some(Box::new(|_ctx: &mut IOContext| true));
+        let (result, output) = execute(runtime, "idle.php");
+        runtime.callbacks.aborted = None;
+        assert_eq!(result.unwrap().termination, Termination::Aborted);
+        assert_eq!(output, "");
+    }
+
+    fn check_function(runtime: &mut Runtime<IOContext>) {
+        let (result, output) = execute(runtime, "function.php");
+        assert_eq!(result.unwrap().termination, Termination::Completed);
+        assert_eq!(output, "7,RuntimeException:quota exceeded:429,variadic");
+    }
+
+    fn execute(
+        runtime: &mut Runtime<IOContext>,
+        script: &str,
+    ) -> (Result<Execution, Error>, String) {
+        let mut ctx = IOContext {
+            body: Box::new([]),
+            buffer: Vec::new(),
+        };
+        let path = ::std::env::current_dir()
+            .unwrap()
+            .join("tests")
+            .join(script);
+        let result = runtime.execute(path.to_str().unwrap(), &mut ctx);
+        (result, String::from_utf8(ctx.buffer).unwrap())
+    }
+
+    fn check_termination(runtime: &mut Runtime<IOContext>) {
+        let (result, output) = execute(runtime, "completed.php");
+        assert_eq!(result.unwrap().termination, Termination::Completed);
+        assert_eq!(output, "done");
+
+        let (result, output) = execute(runtime, "exit.php");
+        let execution = result.unwrap();
+        assert_eq!(execution.termination, Termination::Exit(3));
+        assert_eq!(execution.exit_status(), 3);
+        assert_eq!(output, "bye");
+
+        match execute(runtime, "fatal.php").0 {
+            Err(Error::Fatal {
+                message,
+                line,
+                diagnostics,
+                ..
+            }) => {
+                assert_eq!(message, "out of coffee");
+                assert_eq!(line, 3);
+                assert!(diagnostics[0].message.starts_with("Undefined variable"));
+            }
+            other => panic!("expected a fatal error, got {:?}", other),
+        }
+
+        // no exception handler is set up behind the script's back
+        let (result, output) = execute(runtime, "exception.php");
+        assert!(output.starts_with("no handler"), "{}", output);
+        match result {
+            Err(Error::UncaughtException { exception, .. }) => {
+                assert_eq!(exception.class, "RuntimeException");
+                assert_eq!(exception.code, 404);
+                assert_eq!(exception.line, 3);
+                assert_eq!(exception.trace[0].function, "load");
+                let previous = exception.previous.expect("the previous exception");
+                assert_eq!(previous.class, "LogicException");
+            }
+            other => panic!("expected an uncaught exception, got {:?}", other),
+        }
+
+        // the runtime is still usable after a request failed
+        assert_eq!(execute(runtime, "completed.php").1, "done");
+    }
+
+    fn check_session(runtime: &mut Runtime<IOContext>) {
+        let mut ctx = IOContext {
+            body: Box::new([]),
+            buffer: Vec::new(),
+        };
+        let request = Request::new("repl");
+        let mut session = runtime.session(&request, &mut ctx).unwrap();
+        assert_eq!(session.eval("$a = 1; $b = 2;").unwrap(), None);
+        assert_eq!(session.eval("$a + $b").unwrap(), Some("3".to_owned()));
+        assert_eq!(
+            session.eval("$a = [1];").unwrap(),
+            Some("array (\n  0 => 1,\n)".to_owned())
+        );
     }
 }
@@ -0,0 +1,73 @@
+//! The OPcache statistics of a runtime.
+
+/// What OPcache reports about its cache, like `opcache_get_status()` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcacheStatus {
+    /// Whether the cache is in use, it is turned off if OPcache ran out of
+    /// memory for it or failed to start.
+    pub enabled: bool,
+    /// The number of times a script was found in the cache.
+    pub hits: u64,
+    /// The number of times a script had to be compiled.
+    pub misses: u64,
+    /// The number of scripts in the cache.
+    pub cached_scripts: u64,
+    /// The shared memory in use, in bytes.
+    pub used_memory: u64,
+    /// The shared memory left, in bytes.
+    pub free_memory: u64,
+    /// The shared memory taken by outdated scripts, in bytes.
+    pub wasted_memory: u64,
+}
+
+impl OpcacheStatus {
+    /// The share of script loads the cache served, between 0 and 1.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+
+    /// Reads the status in the current request, `None` if OPcache is not
+    /// loaded or not running.
+    pub(crate) unsafe fn current() -> Option<OpcacheStatus> {
+        let mut status = php_sys::phprs_opcache_status_t::default();
+        if php_sys::phprs_opcache_status(&mut status) == 0 {
+            return None;
+        }
+        Some(OpcacheStatus {
+            enabled: status.enabled != 0,
+            hits: status.hits as u64,
+            misses: status.misses as u64,
+            cached_scripts: status.cached_scripts as u64,
+            used_memory: status.used_memory as u64,
+            free_memory: status.free_memory as u64,
+            wasted_memory: status.wasted_memory as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hit_rate() {
+        let mut status = OpcacheStatus {
+            enabled: true,
+            hits: 0,
+            misses: 0,
+            cached_scripts: 0,
+            used_memory: 0,
+            free_memory: 0,
+            wasted_memory: 0,
+        };
+        assert_eq!(status.hit_rate(), 0.0);
+        status.hits = 3;
+        status.misses = 1;
+        assert_eq!(status.hit_rate(), 0.75);
+    }
+}
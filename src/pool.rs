@@ -0,0 +1,146 @@
+//! A pool of worker threads that each run PHP requests on their own TSRM
+//! resources.
+
+use std::fmt;
+#[cfg(php_zts)]
+use std::ptr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::{Callbacks, Error, Execution, Request, Runtime};
+
+/// Called with the context and the result once a request is done.
+type Done<T> = Box<dyn FnOnce(T, Result<Execution, Error>) + Send>;
+
+struct Job<T> {
+    request: Request,
+    context: T,
+    done: Done<T>,
+}
+
+/// A pool of PHP worker threads.
+///
+/// The pool is created with `RuntimeBuilder::start_pool`. Requests are sent
+/// to the workers over a channel together with their context, the first idle
+/// worker picks them up. Dropping the pool waits for all queued requests to
+/// finish.
+pub struct RuntimePool<T> {
+    jobs: Mutex<Option<mpsc::Sender<Job<T>>>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl<T> RuntimePool<T>
+where
+    T: fmt::Debug + Send + 'static,
+{
+    pub(crate) fn new<F>(threads: usize, factory: F) -> Self
+    where
+        F: Fn(usize) -> Callbacks<T> + Send + Sync + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let factory = Arc::new(factory);
+        let workers = (0..threads)
+            .map(|index| {
+                let receiver = receiver.clone();
+                let factory = factory.clone();
+                thread::Builder::new()
+                    .name(format!("php-worker-{}", index))
+                    .spawn(move || worker(index, &receiver, &*factory))
+                    .expect("failed to spawn php worker")
+            })
+            .collect();
+        RuntimePool {
+            jobs: Mutex::new(Some(sender)),
+            workers,
+        }
+    }
+
+    /// Queues a php file to be executed with the given context. `done` is
+    /// called on the worker thread with the context and the result once the
+    /// request finished.
+    pub fn spawn<F>(&self, handle_filename: &str, context: T, done: F)
+    where
+        F: FnOnce(T, Result<Execution, Error>) + Send + 'static,
+    {
+        self.spawn_request(Request::new(handle_filename).method("POST"), context, done)
+    }
+
+    /// Queues a request to be executed with the given context, see `spawn`.
+    pub fn spawn_request<F>(&self, request: Request, context: T, done: F)
+    where
+        F: FnOnce(T, Result<Execution, Error>) + Send + 'static,
+    {
+        let job = Job {
+            request,
+            context,
+            done: Box::new(done),
+        };
+        let jobs = self.jobs.lock().unwrap();
+        let sent = match *jobs {
+            Some(ref sender) => sender.send(job).map_err(|mpsc::SendError(job)| job),
+            None => Err(job),
+        };
+        if let Err(job) = sent {
+            // all workers are gone, there is nobody left to run this
+            (job.done)(job.context, Err(Error::Unavailable));
+        }
+    }
+
+    /// Executes a php file on the next free worker and waits for it to
+    /// finish. The context is handed back together with the result, it is
+    /// lost with `Error::Unavailable` if the worker died while running it.
+    pub fn execute(
+        &self,
+        handle_filename: &str,
+        context: T,
+    ) -> (Option<T>, Result<Execution, Error>) {
+        let (sender, receiver) = mpsc::channel();
+        self.spawn(handle_filename, context, move |context, result| {
+            let _ = sender.send((context, result));
+        });
+        match receiver.recv() {
+            Ok((context, result)) => (Some(context), result),
+            Err(mpsc::RecvError) => (None, Err(Error::Unavailable)),
+        }
+    }
+}
+
+impl<T> Drop for RuntimePool<T> {
+    fn drop(&mut self) {
+        // Hanging up the channel stops the workers once the queue is drained.
+        self.jobs.lock().unwrap().take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker<T, F>(index: usize, jobs: &Mutex<mpsc::Receiver<Job<T>>>, factory: &F)
+where
+    T: fmt::Debug,
+    F: Fn(usize) -> Callbacks<T>,
+{
+    #[cfg(php_zts)]
+    unsafe {
+        php_sys::ts_resource_ex(0, ptr::null_mut());
+        php_sys::zend_tsrmls_cache_update();
+    }
+    let mut runtime = Runtime {
+        callbacks: factory(index),
+    };
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => break,
+        };
+        let mut context = job.context;
+        let result = runtime.execute_request(&job.request, &mut context);
+        (job.done)(context, result);
+    }
+    #[cfg(php_zts)]
+    unsafe {
+        php_sys::ts_free_thread();
+    }
+}
@@ -0,0 +1,257 @@
+//! Per request information handed to PHP.
+
+use std::env;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+
+/// The information PHP gets about a request, it ends up in
+/// `SG(request_info)` and `$_SERVER`.
+#[derive(Debug, Clone)]
+pub struct Request {
+    script: String,
+    method: String,
+    uri: String,
+    query_string: String,
+    content_type: Option<String>,
+    content_length: Option<u64>,
+    cookies: Option<String>,
+    server: Vec<(String, String)>,
+    args: Option<Vec<String>>,
+    cli: bool,
+}
+
+impl Request {
+    /// Creates a `GET` request for the given php file.
+    pub fn new(handle_filename: &str) -> Self {
+        Request {
+            script: handle_filename.to_owned(),
+            method: "GET".to_owned(),
+            uri: "/".to_owned(),
+            query_string: String::new(),
+            content_type: None,
+            content_length: None,
+            cookies: None,
+            server: Vec::new(),
+            args: None,
+            cli: false,
+        }
+    }
+
+    /// Sets the request method, `REQUEST_METHOD`.
+    pub fn method(mut self, method: &str) -> Self {
+        self.method = method.to_owned();
+        self
+    }
+
+    /// Sets the request uri, `REQUEST_URI`.
+    pub fn uri(mut self, uri: &str) -> Self {
+        self.uri = uri.to_owned();
+        self
+    }
+
+    /// Sets the query string without the leading `?`, it is parsed into
+    /// `$_GET`.
+    pub fn query_string(mut self, query_string: &str) -> Self {
+        self.query_string = query_string.to_owned();
+        self
+    }
+
+    /// Sets the content type of the body, it decides how `$_POST` is parsed.
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_owned());
+        self
+    }
+
+    /// Sets the length of the body.
+    pub fn content_length(mut self, content_length: u64) -> Self {
+        self.content_length = Some(content_length);
+        self
+    }
+
+    /// Sets the raw `Cookie` header, it is parsed into `$_COOKIE`.
+    pub fn cookies(mut self, cookies: &str) -> Self {
+        self.cookies = Some(cookies.to_owned());
+        self
+    }
+
+    /// Adds a variable to `$_SERVER`, e.g. `HTTP_HOST`. Variables set here
+    /// take precedence over the ones derived from the request.
+    pub fn server_var(mut self, name: &str, value: &str) -> Self {
+        self.server.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Sets the command line arguments. `$argv` is the script followed by
+    /// them and `$argc` its length, without them both are derived from the
+    /// query string like for any other SAPI.
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.args = Some(args.into_iter().map(|a| a.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Runs the request like php-cli does. The working directory is not
+    /// changed to the one of the script, the `STDIN`, `STDOUT` and `STDERR`
+    /// constants are defined and `$_SERVER` holds the environment instead of
+    /// the request variables.
+    pub fn cli(mut self) -> Self {
+        self.cli = true;
+        self
+    }
+
+    /// The php file executed for this request.
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// Whether the request runs like php-cli.
+    pub(crate) fn is_cli(&self) -> bool {
+        self.cli
+    }
+
+    /// The cookie header as it is handed to PHP.
+    pub(crate) fn cookie_data(&self) -> Option<CString> {
+        self.cookies.as_ref().map(|c| c_string(c))
+    }
+
+    /// All `$_SERVER` variables of the request, the derived ones first.
+    pub(crate) fn server_vars(&self) -> Vec<(CString, String)> {
+        let mut vars = if self.cli {
+            // the environment need not be UTF-8, PHP gets it converted lossily
+            let mut vars: Vec<_> = env::vars_os()
+                .map(|(k, v)| {
+                    (
+                        c_string(&k.to_string_lossy()),
+                        v.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect();
+            for name in &[
+                "PHP_SELF",
+                "SCRIPT_NAME",
+                "SCRIPT_FILENAME",
+                "PATH_TRANSLATED",
+            ] {
+                vars.push((c_string(name), self.script.clone()));
+            }
+            vars.push((c_string("DOCUMENT_ROOT"), String::new()));
+            vars
+        } else {
+            let mut vars = vec![
+                (c_string("REQUEST_METHOD"), self.method.clone()),
+                (c_string("REQUEST_URI"), self.uri.clone()),
+                (c_string("QUERY_STRING"), self.query_string.clone()),
+                (c_string("SCRIPT_FILENAME"), self.script.clone()),
+            ];
+            if let Some(ref content_type) = self.content_type {
+                vars.push((c_string("CONTENT_TYPE"), content_type.clone()));
+            }
+            if let Some(content_length) = self.content_length {
+                vars.push((c_string("CONTENT_LENGTH"), content_length.to_string()));
+            }
+            vars
+        };
+        for (name, value) in &self.server {
+            vars.push((c_string(name), value.clone()));
+        }
+        vars
+    }
+
+    /// `$argv` as it is handed to PHP, the script comes first.
+    fn argv(&self) -> Vec<CString> {
+        match self.args {
+            Some(ref args) => {
+                let mut argv = vec![c_string(&self.script)];
+                argv.extend(args.iter().map(|a| c_string(a)));
+                argv
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Keeps the strings `SG(request_info)` points to alive for the duration of
+/// a request.
+pub(crate) struct RequestInfo {
+    method: CString,
+    uri: CString,
+    query_string: CString,
+    path_translated: CString,
+    content_type: Option<CString>,
+    argv: Vec<CString>,
+    argv_ptrs: Vec<*mut c_char>,
+}
+
+impl RequestInfo {
+    /// Fills `SG(request_info)` of the current thread, the returned value has
+    /// to be kept until the request is shut down.
+    pub(crate) unsafe fn install(request: &Request) -> Self {
+        let argv = request.argv();
+        let mut argv_ptrs: Vec<_> = argv.iter().map(|a| a.as_ptr() as *mut c_char).collect();
+        argv_ptrs.push(ptr::null_mut());
+        let mut strings = RequestInfo {
+            method: c_string(&request.method),
+            uri: c_string(&request.uri),
+            query_string: c_string(&request.query_string),
+            path_translated: c_string(&request.script),
+            content_type: request.content_type.as_ref().map(|c| c_string(c)),
+            argv,
+            argv_ptrs,
+        };
+        let info = php_sys::sg_request_info();
+        // php-cli has no request method, scripts use that to tell the two
+        // apart
+        (*info).request_method = if request.cli {
+            ptr::null()
+        } else {
+            strings.method.as_ptr()
+        };
+        (*info).request_uri = strings.uri.as_ptr() as *mut c_char;
+        (*info).query_string = strings.query_string.as_ptr() as *mut c_char;
+        (*info).path_translated = strings.path_translated.as_ptr() as *mut c_char;
+        (*info).content_type = strings
+            .content_type
+            .as_ref()
+            .map(|c| c.as_ptr())
+            .unwrap_or(ptr::null());
+        (*info).content_length = request.content_length.unwrap_or(0) as php_sys::zend_long;
+        (*info).argc = strings.argv.len() as c_int;
+        (*info).argv = if strings.argv.is_empty() {
+            ptr::null_mut()
+        } else {
+            strings.argv_ptrs.as_mut_ptr()
+        };
+
+        let options = php_sys::sg_options();
+        if request.cli {
+            php_sys::sg_set_options(options | php_sys::SAPI_OPTION_NO_CHDIR as c_int);
+        } else {
+            php_sys::sg_set_options(options & !(php_sys::SAPI_OPTION_NO_CHDIR as c_int));
+        }
+        strings
+    }
+
+    /// Clears `SG(request_info)` again, after this the strings can be dropped.
+    pub(crate) unsafe fn uninstall(self) {
+        let info = php_sys::sg_request_info();
+        (*info).request_method = ptr::null();
+        (*info).request_uri = ptr::null_mut();
+        (*info).query_string = ptr::null_mut();
+        (*info).path_translated = ptr::null_mut();
+        (*info).content_type = ptr::null();
+        (*info).content_length = 0;
+        (*info).argc = 0;
+        (*info).argv = ptr::null_mut();
+    }
+}
+
+/// Turns a string into a C string, PHP can't see past a nul byte anyway so
+/// the string is cut there.
+pub(crate) fn c_string(s: &str) -> CString {
+    let end = s.find('\0').unwrap_or_else(|| s.len());
+    CString::new(&s[..end]).unwrap()
+}
@@ -0,0 +1,536 @@
+//! A `tower::Service` serving a PHP application from a document root.
+
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::future;
+use futures::{Stream, StreamExt};
+use http::header::CONTENT_TYPE;
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use http_body::Frame;
+use http_body_util::BodyStream;
+use tokio::fs::File;
+use tokio_util::io::ReaderStream;
+use tower_service::Service;
+
+use super::http::{request_info, response};
+use super::{AsyncRequest, AsyncRuntime, Error, Output};
+
+/// The body of a response produced by a `PhpService`.
+pub struct Body {
+    kind: BodyKind,
+}
+
+enum BodyKind {
+    Full(Option<Bytes>),
+    Output(Output, Option<ErrorHook>),
+    File(ReaderStream<File>),
+}
+
+/// Told about requests that failed, see `PhpService::on_error`.
+type ErrorHook = Arc<dyn Fn(&Error) + Send + Sync>;
+
+impl Body {
+    /// A body that consists of a single chunk.
+    pub fn full<B: Into<Bytes>>(bytes: B) -> Self {
+        Body {
+            kind: BodyKind::Full(Some(bytes.into())),
+        }
+    }
+
+    /// A body streaming the output of a PHP request.
+    pub fn output(output: Output) -> Self {
+        Body {
+            kind: BodyKind::Output(output, None),
+        }
+    }
+
+    /// Like `output`, `hook` is called with the error if the script fails.
+    fn reporting(output: Output, hook: Option<ErrorHook>) -> Self {
+        Body {
+            kind: BodyKind::Output(output, hook),
+        }
+    }
+}
+
+impl http_body::Body for Body {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        match self.get_mut().kind {
+            BodyKind::Full(ref mut bytes) => Poll::Ready(bytes.take().map(|b| Ok(Frame::data(b)))),
+            BodyKind::Output(ref mut output, ref mut hook) => {
+                match Pin::new(&mut *output).poll_next(cx) {
+                    Poll::Ready(Some(chunk)) => return Poll::Ready(Some(Ok(Frame::data(chunk)))),
+                    Poll::Ready(None) => (),
+                    Poll::Pending => return Poll::Pending,
+                }
+                // the output ends with the script, only the hook is waiting
+                if let Some(ref report) = *hook {
+                    match output.poll_result(cx) {
+                        Poll::Ready(Err(e)) => report(&e),
+                        Poll::Ready(Ok(_)) => (),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                *hook = None;
+                Poll::Ready(None)
+            }
+            // A file that fails half way can't be turned into an error
+            // response any more, the body just ends.
+            BodyKind::File(ref mut file) => match Pin::new(file).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => Poll::Ready(Some(Ok(Frame::data(chunk)))),
+                Poll::Ready(Some(Err(_))) | Poll::Ready(None) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            BodyKind::Full(ref bytes) => f.debug_tuple("Body").field(bytes).finish(),
+            BodyKind::Output(..) => f.debug_tuple("Body").field(&"<php output>").finish(),
+            BodyKind::File(_) => f.debug_tuple("Body").field(&"<file>").finish(),
+        }
+    }
+}
+
+/// Where a request is routed to inside the document root.
+#[derive(Debug, PartialEq)]
+struct Route {
+    /// The php file that is executed.
+    script: PathBuf,
+    /// The uri path of the script.
+    script_name: String,
+    /// The part of the path after the script.
+    path_info: String,
+}
+
+/// A document root and the front controller handling everything that is not
+/// a php file in it.
+#[derive(Debug, Clone)]
+struct DocumentRoot {
+    path: PathBuf,
+    front_controller: String,
+}
+
+impl DocumentRoot {
+    /// Finds a file that is not a php script at the decoded path. Hidden
+    /// files like `.env` or anything under `.git/` are never served.
+    fn static_file(&self, path: &str) -> Option<PathBuf> {
+        if path.split('/').any(|segment| segment.starts_with('.')) {
+            return None;
+        }
+        let file = self.resolve(path)?;
+        if !is_php(path) && file.is_file() {
+            Some(file)
+        } else {
+            None
+        }
+    }
+
+    /// Finds the script the decoded path is handled by.
+    fn route(&self, path: &str) -> Route {
+        let mut script_name = String::new();
+        let mut segments = path.split('/').filter(|s| !s.is_empty());
+        while let Some(segment) = segments.next() {
+            script_name.push('/');
+            script_name.push_str(segment);
+            if !is_php(segment) {
+                continue;
+            }
+            if let Some(script) = self.resolve(&script_name) {
+                if script.is_file() {
+                    let path_info: Vec<&str> = segments.collect();
+                    let path_info = if path_info.is_empty() {
+                        String::new()
+                    } else {
+                        format!("/{}", path_info.join("/"))
+                    };
+                    return Route {
+                        script,
+                        script_name,
+                        path_info,
+                    };
+                }
+            }
+        }
+        if let Some(dir) = self.resolve(path) {
+            let index = dir.join("index.php");
+            if dir.is_dir() && index.is_file() {
+                let mut script_name = path.trim_end_matches('/').to_owned();
+                script_name.push_str("/index.php");
+                return Route {
+                    script: index,
+                    script_name,
+                    path_info: String::new(),
+                };
+            }
+        }
+        Route {
+            script: self.path.join(&self.front_controller),
+            script_name: format!("/{}", self.front_controller),
+            path_info: path.to_owned(),
+        }
+    }
+
+    /// Maps a uri path into the document root, paths trying to escape it are
+    /// rejected.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let relative = Path::new(path.trim_start_matches('/'));
+        if relative
+            .components()
+            .any(|c| c != Component::CurDir && !matches!(c, Component::Normal(_)))
+        {
+            return None;
+        }
+        Some(self.path.join(relative))
+    }
+}
+
+/// Whether a path names a php script, the extension is matched in any case.
+fn is_php(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.eq_ignore_ascii_case("php"))
+        .unwrap_or(false)
+}
+
+/// Decodes the `%XX` escapes of a path, `None` if it is malformed or does
+/// not decode to UTF-8 without NUL bytes.
+fn percent_decode(path: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail
+                .get(..2)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// A `tower::Service` that runs PHP scripts from a document root.
+///
+/// Requests for a `.php` file that exists in the document root run that
+/// file, everything else is handed to the front controller script. Errors
+/// are turned into `500` responses so the service never fails and can be
+/// mounted in an axum router directly, `on_error` sees them on the way.
+///
+/// If the request carries a `SocketAddr` extension it is passed to PHP as
+/// `REMOTE_ADDR` and `REMOTE_PORT`.
+#[derive(Clone)]
+pub struct PhpService {
+    runtime: AsyncRuntime,
+    root: DocumentRoot,
+    static_files: bool,
+    error: Option<ErrorHook>,
+}
+
+impl PhpService {
+    /// Creates a service serving `document_root`. `front_controller` is the
+    /// path of the script, relative to the document root, that handles every
+    /// request not pointing to a php file, e.g. `index.php`.
+    pub fn new<P: Into<PathBuf>>(
+        runtime: AsyncRuntime,
+        document_root: P,
+        front_controller: &str,
+    ) -> Self {
+        PhpService {
+            runtime,
+            root: DocumentRoot {
+                path: document_root.into(),
+                front_controller: front_controller.trim_start_matches('/').to_owned(),
+            },
+            static_files: false,
+            error: None,
+        }
+    }
+
+    /// Serves files in the document root that are not php scripts as they
+    /// are instead of handing them to the front controller.
+    pub fn static_files(mut self, enabled: bool) -> Self {
+        self.static_files = enabled;
+        self
+    }
+
+    /// Sets a hook that is told about every request that failed. The client
+    /// gets a `500` for it, or a body that ends early if the script already
+    /// sent its headers.
+    pub fn on_error(mut self, hook: Box<dyn Fn(&Error) + Send + Sync>) -> Self {
+        self.error = Some(Arc::from(hook));
+        self
+    }
+
+    /// Runs the request through PHP.
+    pub async fn handle<B>(&self, request: Request<B>) -> Response<Body>
+    where
+        B: http_body::Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        // static files and scripts are looked up by the same decoded path
+        let path = match percent_decode(request.uri().path()) {
+            Some(path) => path,
+            None => return error(StatusCode::BAD_REQUEST),
+        };
+        if self.static_files
+            && (request.method() == Method::GET || request.method() == Method::HEAD)
+        {
+            if let Some(file) = self.root.static_file(&path) {
+                return serve_file(&file, request.method() == Method::HEAD).await;
+            }
+        }
+        let route = self.root.route(&path);
+        let script = route.script.to_string_lossy().into_owned();
+        let document_root = self.root.path.to_string_lossy().into_owned();
+        let mut php_self = route.script_name.clone();
+        php_self.push_str(&route.path_info);
+        let mut info = request_info(&script, &request)
+            .server_var("DOCUMENT_ROOT", &document_root)
+            .server_var("SCRIPT_NAME", &route.script_name)
+            .server_var("PHP_SELF", &php_self);
+        if !route.path_info.is_empty() {
+            info = info.server_var("PATH_INFO", &route.path_info);
+        }
+        if let Some(remote) = request.extensions().get::<SocketAddr>() {
+            info = info
+                .server_var("REMOTE_ADDR", &remote.ip().to_string())
+                .server_var("REMOTE_PORT", &remote.port().to_string());
+        }
+
+        let body = BodyStream::new(request.into_body()).filter_map(|frame| {
+            future::ready(match frame {
+                Ok(frame) => frame.into_data().ok().map(Ok),
+                Err(e) => Some(Err(io::Error::new(io::ErrorKind::Other, e.into()))),
+            })
+        });
+        let request = AsyncRequest::from_request(info).body_stream(body);
+        let mut output = self.runtime.execute(request).await;
+        match output.head().await {
+            Some(head) => {
+                let body = Body::reporting(output, self.error.clone());
+                response(head.status, &head.headers, body)
+            }
+            None => {
+                if let (Err(e), Some(hook)) = (output.finish().await, self.error.as_ref()) {
+                    hook(&e);
+                }
+                error(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for PhpService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PhpService")
+            .field("root", &self.root)
+            .field("static_files", &self.static_files)
+            .finish()
+    }
+}
+
+impl<B> Service<Request<B>> for PhpService
+where
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.handle(request).await) })
+    }
+}
+
+/// An empty response with the given status.
+fn error(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::full(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Responds with the content of a file.
+async fn serve_file(path: &Path, head_only: bool) -> Response<Body> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return error(StatusCode::NOT_FOUND),
+        Err(_) => return error(StatusCode::FORBIDDEN),
+    };
+    let body = if head_only {
+        Body::full(Bytes::new())
+    } else {
+        Body {
+            kind: BodyKind::File(ReaderStream::new(file)),
+        }
+    };
+    let mut response = Response::new(body);
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type(path)));
+    response
+}
+
+/// Guesses the content type of a static file from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_ref().map(|e| e.as_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "text/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_route() {
+        let root = std::env::temp_dir().join(format!("php-rs-route-{}", std::process::id()));
+        fs::create_dir_all(root.join("admin")).unwrap();
+        fs::write(root.join("index.php"), "").unwrap();
+        fs::write(root.join("info.php"), "").unwrap();
+        fs::write(root.join("admin/index.php"), "").unwrap();
+        fs::write(root.join("Upper.PHP"), "").unwrap();
+        let service = DocumentRoot {
+            path: root.clone(),
+            front_controller: "index.php".to_owned(),
+        };
+
+        assert_eq!(
+            service.route("/info.php/a/b"),
+            Route {
+                script: root.join("info.php"),
+                script_name: "/info.php".to_owned(),
+                path_info: "/a/b".to_owned(),
+            }
+        );
+        assert_eq!(
+            service.route("/admin/"),
+            Route {
+                script: root.join("admin/index.php"),
+                script_name: "/admin/index.php".to_owned(),
+                path_info: "".to_owned(),
+            }
+        );
+        assert_eq!(
+            service.route("/users/42"),
+            Route {
+                script: root.join("index.php"),
+                script_name: "/index.php".to_owned(),
+                path_info: "/users/42".to_owned(),
+            }
+        );
+        assert_eq!(
+            service.route("/Upper.PHP/a"),
+            Route {
+                script: root.join("Upper.PHP"),
+                script_name: "/Upper.PHP".to_owned(),
+                path_info: "/a".to_owned(),
+            }
+        );
+        assert_eq!(
+            service.route("/../etc/passwd.php").script,
+            root.join("index.php")
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_static_file() {
+        let root = std::env::temp_dir().join(format!("php-rs-static-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.php"), "").unwrap();
+        fs::write(root.join("style.css"), "").unwrap();
+        fs::write(root.join("my style.css"), "").unwrap();
+        fs::write(root.join("secret.PHP"), "").unwrap();
+        fs::write(root.join(".env"), "").unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".git/config"), "").unwrap();
+        let service = DocumentRoot {
+            path: root.clone(),
+            front_controller: "index.php".to_owned(),
+        };
+
+        assert_eq!(
+            service.static_file("/style.css"),
+            Some(root.join("style.css"))
+        );
+        assert_eq!(service.static_file("/index.php"), None);
+        assert_eq!(service.static_file("/missing.css"), None);
+        assert_eq!(service.static_file("/"), None);
+        assert_eq!(
+            service.static_file("/my style.css"),
+            Some(root.join("my style.css"))
+        );
+        assert_eq!(service.static_file("/secret.PHP"), None);
+        assert_eq!(service.static_file("/.env"), None);
+        assert_eq!(service.static_file("/.git/config"), None);
+        assert_eq!(service.static_file("/../etc/passwd"), None);
+        assert_eq!(
+            content_type(Path::new("a/b.CSS")),
+            "text/css; charset=utf-8"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("/my%20style.css").unwrap(), "/my style.css");
+        assert_eq!(percent_decode("/a%2Ephp").unwrap(), "/a.php");
+        assert_eq!(percent_decode("/%2e%2e/etc").unwrap(), "/../etc");
+        assert_eq!(percent_decode("/style.css%00"), None);
+        assert_eq!(percent_decode("/style%2"), None);
+        assert_eq!(percent_decode("/style%+1"), None);
+        assert_eq!(percent_decode("/%C3"), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! A request that is kept alive to evaluate code in, e.g. for a REPL.
+
+use std::mem;
+use std::os::raw::{c_char, c_int, c_void};
+use std::slice;
+
+use super::error::last_fatal_error;
+use super::{end_request, Error, PHPContext, RequestInfo};
+
+/// Why evaluating code in a `Session` failed.
+#[derive(Debug)]
+pub enum EvalError {
+    /// The code does not parse, with PHP's message.
+    Parse(String),
+    /// The code called `exit()`, with the exit status. The session can still
+    /// be used.
+    Exit(i32),
+    /// The code hit a fatal error or a callback panicked, the session can
+    /// still be used.
+    Fatal(Error),
+}
+
+/// A running request created by `Runtime::session`, it is shut down when
+/// dropped.
+pub struct Session<'a, T: 'a> {
+    context: *mut PHPContext<'a, T>,
+    info: Option<RequestInfo>,
+}
+
+impl<'a, T> Session<'a, T> {
+    pub(crate) fn new(context: *mut PHPContext<'a, T>, info: RequestInfo) -> Self {
+        Session {
+            context,
+            info: Some(info),
+        }
+    }
+
+    /// Evaluates code in the request. If it is a single expression, which may
+    /// end with a `;`, its value is returned the way `var_export` prints it.
+    /// Statements return `None`. Output goes through the write callback as
+    /// usual.
+    pub fn eval(&mut self, code: &str) -> Result<Option<String>, EvalError> {
+        // PHP runs an expression as `return <code>;`, which would stop after
+        // the first of several statements.
+        if is_expression(code) {
+            let mut value = String::new();
+            // Code only runs if it parses, so what turns out not to be an
+            // expression is not run twice.
+            match eval(code, true, &mut value) {
+                php_sys::PHPRS_EVAL_OK => return Ok(Some(value)),
+                php_sys::PHPRS_EVAL_PARSE_ERROR => (),
+                _ => return Err(self.bailout()),
+            }
+        }
+        let mut message = String::new();
+        match eval(code, false, &mut message) {
+            php_sys::PHPRS_EVAL_OK => Ok(None),
+            php_sys::PHPRS_EVAL_PARSE_ERROR => Err(EvalError::Parse(message)),
+            _ => Err(self.bailout()),
+        }
+    }
+
+    /// Tells apart why the last evaluation bailed out.
+    fn bailout(&mut self) -> EvalError {
+        unsafe {
+            // the diagnostics since the last error belong to this one
+            let diagnostics = mem::replace(&mut (*self.context).diagnostics, Vec::new());
+            if let Some(payload) = (*self.context).panic.take() {
+                return EvalError::Fatal(Error::panic(payload).with_diagnostics(diagnostics));
+            }
+            match last_fatal_error() {
+                Some(error) => EvalError::Fatal(error.with_diagnostics(diagnostics)),
+                None => EvalError::Exit(php_sys::eg_exit_status() as i32),
+            }
+        }
+    }
+}
+
+impl<'a, T> Drop for Session<'a, T> {
+    fn drop(&mut self) {
+        if let Some(info) = self.info.take() {
+            unsafe {
+                end_request(self.context, info);
+            }
+        }
+    }
+}
+
+/// Whether `code` can be run as `return <code>;`, i.e. it has no `;` or `?>`
+/// outside of strings, comments and brackets except for a single `;` at its
+/// end. Code this can't tell apart, e.g. with heredocs, counts as statements.
+fn is_expression(code: &str) -> bool {
+    let bytes = code.as_bytes();
+    let mut depth = 0usize;
+    let mut end = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        match c {
+            b'#' => i = line_end(bytes, i),
+            b'/' if bytes.get(i + 1) == Some(&b'/') => i = line_end(bytes, i),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => match code[i + 2..].find("*/") {
+                Some(close) => i += close + 3,
+                None => return false,
+            },
+            // only whitespace and comments may follow the final `;`
+            _ if end => return false,
+            b'\'' | b'"' | b'`' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != c {
+                    if bytes[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                if i >= bytes.len() {
+                    return false;
+                }
+            }
+            b'<' if code[i..].starts_with("<<<") => return false,
+            b'?' if bytes.get(i + 1) == Some(&b'>') => return false,
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => match depth.checked_sub(1) {
+                Some(d) => depth = d,
+                None => return false,
+            },
+            b';' if depth == 0 => end = true,
+            _ => (),
+        }
+        i += 1;
+    }
+    depth == 0
+}
+
+/// The index of the newline ending the line `i` is on.
+fn line_end(bytes: &[u8], i: usize) -> usize {
+    bytes[i..]
+        .iter()
+        .position(|&c| c == b'\n')
+        .map_or(bytes.len(), |n| i + n)
+}
+
+fn eval(code: &str, expression: bool, out: &mut String) -> c_int {
+    unsafe {
+        php_sys::phprs_clear_last_error();
+        php_sys::phprs_eval(
+            code.as_ptr() as *const c_char,
+            code.len(),
+            expression as c_int,
+            Some(collect_string),
+            out as *mut String as *mut c_void,
+        )
+    }
+}
+
+unsafe extern "C" fn collect_string(out: *mut c_void, s: *const c_char, len: usize) {
+    let out = &mut *(out as *mut String);
+    out.push_str(&String::from_utf8_lossy(slice::from_raw_parts(
+        s as *const u8,
+        len,
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_expression() {
+        assert!(is_expression("count($a)"));
+        assert!(is_expression("$a = [1, 2];"));
+        assert!(is_expression("$f = function () { $a = 1; return $a; };"));
+        assert!(is_expression("strlen('a;b') /* done; */"));
+        assert!(!is_expression("$a = 1; $b = 2;"));
+        assert!(!is_expression("$a = 1; $b = 2"));
+        assert!(!is_expression("$a = 1 ?> <?php $b = 2"));
+        assert!(!is_expression("echo \"a\\\";b\"; $c = 3"));
+        assert!(!is_expression("$a = <<<EOT\nx\nEOT;"));
+    }
+}
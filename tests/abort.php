@@ -0,0 +1,8 @@
+<?php
+register_shutdown_function(function () {
+    file_put_contents($_SERVER["ABORT_FILE"], connection_status());
+});
+while (true) {
+    echo str_repeat("x", 1024);
+    flush();
+}
@@ -0,0 +1,78 @@
+//! Runs requests through the `AsyncRuntime`. This lives in its own test
+//! binary since PHP can only be started once per process.
+#![cfg(feature = "async")]
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
+use php::{AsyncRequest, AsyncRuntime, Request, Runtime, Termination};
+
+fn script(name: &str) -> String {
+    let path = env::current_dir().unwrap().join("tests").join(name);
+    path.to_str().unwrap().to_owned()
+}
+
+async fn check_stream(runtime: &AsyncRuntime) {
+    let (mut body, receiver) = mpsc::channel::<io::Result<Bytes>>(1);
+    let request = AsyncRequest::new(&script("stream.php")).body_stream(receiver);
+    let mut output = runtime.execute(request).await;
+
+    // the script waits for the body after its first chunk, so both have to
+    // arrive before any of the body is sent
+    let head = output.head().await.unwrap();
+    assert_eq!(head.status, 200);
+    assert!(head.headers.contains(&b"X-Stream: 1".to_vec()));
+    assert_eq!(&output.next().await.unwrap()[..], b"ready");
+
+    body.send(Ok(Bytes::from("hello"))).await.unwrap();
+    drop(body);
+    let mut rest = Vec::new();
+    while let Some(chunk) = output.next().await {
+        rest.extend_from_slice(&chunk);
+    }
+    assert_eq!(rest, b"HELLO");
+
+    let execution = output.finish().await.unwrap();
+    assert_eq!(execution.termination, Termination::Completed);
+}
+
+async fn check_drop(runtime: &AsyncRuntime) {
+    let marker = env::temp_dir().join(format!("php-rs-abort-{}", process::id()));
+    let _ = fs::remove_file(&marker);
+    let request =
+        Request::new(&script("abort.php")).server_var("ABORT_FILE", marker.to_str().unwrap());
+    let mut output = runtime.execute(AsyncRequest::from_request(request)).await;
+    assert!(output.next().await.is_some());
+    drop(output);
+
+    // with a single worker this only runs once the aborted script is done
+    let execution = runtime
+        .execute(AsyncRequest::new(&script("completed.php")))
+        .await
+        .finish()
+        .await
+        .unwrap();
+    assert_eq!(execution.termination, Termination::Completed);
+    // CONNECTION_ABORTED, seen by the shutdown function of the dropped script
+    assert_eq!(fs::read_to_string(&marker).unwrap(), "1");
+    fs::remove_file(&marker).unwrap();
+}
+
+#[test]
+fn test_async_runtime() {
+    let runtime = AsyncRuntime::new(Runtime::new("php-test", "PHP Test Runtime", 1));
+    let tokio = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    tokio.block_on(async {
+        check_stream(&runtime).await;
+        check_drop(&runtime).await;
+    });
+    // joins the worker, so it is dropped outside of the tokio runtime
+    drop(runtime);
+}
@@ -0,0 +1,10 @@
+<?php
+function load() {
+    throw new RuntimeException("no such user", 404, new LogicException("cache miss"));
+}
+try {
+    throw new LogicException("caught");
+} catch (LogicException $e) {
+}
+echo set_exception_handler(null) === null ? "no handler" : "handler";
+load();
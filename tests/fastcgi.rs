@@ -0,0 +1,78 @@
+//! Runs a request through the FastCGI server over TCP. This lives in its own
+//! test binary since PHP can only be started once per process.
+#![cfg(feature = "fastcgi")]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use php::{FastCgiServer, Runtime};
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+
+fn write_record(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    let length = (content.len() as u16).to_be_bytes();
+    out.extend_from_slice(&[1, kind, 0, 1, length[0], length[1], 0, 0]);
+    out.extend_from_slice(content);
+}
+
+fn read_record(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).unwrap();
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0u8; length + header[6] as usize];
+    stream.read_exact(&mut content).unwrap();
+    content.truncate(length);
+    (header[1], content)
+}
+
+fn param(out: &mut Vec<u8>, name: &str, value: &str) {
+    out.push(name.len() as u8);
+    out.push(value.len() as u8);
+    out.extend_from_slice(name.as_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+#[test]
+fn test_serve_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = FastCgiServer::new(Runtime::new("php-test", "PHP Test Runtime", 1));
+    thread::spawn(move || server.serve_tcp_listener(listener));
+
+    let script = ::std::env::current_dir()
+        .unwrap()
+        .join("tests/completed.php");
+    let mut params = Vec::new();
+    param(&mut params, "SCRIPT_FILENAME", script.to_str().unwrap());
+    param(&mut params, "REQUEST_METHOD", "GET");
+    let mut request = Vec::new();
+    write_record(&mut request, BEGIN_REQUEST, &[0, 1, 0, 0, 0, 0, 0, 0]);
+    write_record(&mut request, PARAMS, &params);
+    write_record(&mut request, PARAMS, &[]);
+    write_record(&mut request, STDIN, &[]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&request).unwrap();
+
+    let mut stdout = Vec::new();
+    loop {
+        match read_record(&mut stream) {
+            (STDOUT, ref content) if content.is_empty() => break,
+            (STDOUT, content) => stdout.extend_from_slice(&content),
+            (kind, _) => panic!("unexpected record {}", kind),
+        }
+    }
+    let stdout = String::from_utf8(stdout).unwrap();
+    assert!(stdout.starts_with("Status: 200\r\n"), "{}", stdout);
+    assert!(stdout.ends_with("\r\n\r\ndone"), "{}", stdout);
+
+    let (kind, content) = read_record(&mut stream);
+    assert_eq!(kind, END_REQUEST);
+    // app status 0, FCGI_REQUEST_COMPLETE
+    assert_eq!(&content[..5], &[0, 0, 0, 0, 0]);
+}
@@ -0,0 +1,4 @@
+<?php
+echo $undefined;
+trigger_error("out of coffee", E_USER_ERROR);
+echo "unreachable";
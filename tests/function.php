@@ -0,0 +1,8 @@
+<?php
+echo test_reserve(3), ",";
+try {
+    test_reserve(12);
+} catch (RuntimeException $e) {
+    echo get_class($e), ":", $e->getMessage(), ":", $e->getCode(), ",";
+}
+echo (new ReflectionFunction("test_reserve"))->isVariadic() ? "variadic" : "fixed";
@@ -0,0 +1,6 @@
+<?php
+// writes nothing, it only notices an aborted client when interrupted
+for ($i = 0; $i < 500; $i++) {
+    usleep(10000);
+}
+echo "not aborted";
@@ -0,0 +1,5 @@
+<?php
+header("X-Stream: 1");
+echo "ready";
+flush();
+echo strtoupper(file_get_contents("php://input"));